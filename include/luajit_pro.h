@@ -0,0 +1,183 @@
+#ifndef LUAJIT_PRO_H
+#define LUAJIT_PRO_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Version of the C ABI, bumped on every incompatible change of the types or functions below.
+ */
+#define LJP_ABI_VERSION 1
+
+#define LJP_FLAG_OPT (1 << 0)
+
+#define LJP_FLAG_FORMAT (1 << 1)
+
+#define LJP_FLAG_PRETTY (1 << 2)
+
+#define LJP_FLAG_NO_COMMENT (1 << 3)
+
+#define LJP_FLAG_PRESERVE_LINES (1 << 4)
+
+/**
+ * Never run the optimizer, even for files with the `opt` directive
+ */
+#define LJP_FLAG_DISABLE_OPTIMIZER (1 << 5)
+
+/**
+ * Read the defaults of the `ljp.toml` found above the file
+ */
+#define LJP_FLAG_PROJECT_CONFIG (1 << 6)
+
+/**
+ * Let env vars of the same name override the header params
+ */
+#define LJP_FLAG_ENV_OVERRIDES (1 << 7)
+
+#define LJP_FRONTEND_LUA 0
+
+#define LJP_FRONTEND_TEAL 1
+
+#define LJP_FRONTEND_LUAU 2
+
+#define LJP_CACHE_DISABLED 0
+
+#define LJP_CACHE_READ_WRITE 1
+
+#define LJP_CACHE_REFRESH 2
+
+/**
+ * The outcome of a transformation, owned by the caller until passed to `ljp_free_result()`.
+ */
+typedef struct ljp_result ljp_result;
+
+/**
+ * Options of `ljp_transform_file()` and `ljp_transform_buffer()`.
+ * Passing NULL instead uses the same behavior as the LuaJIT loader(project config, `LJP_*` env vars, build cache).
+ */
+typedef struct ljp_options {
+  /**
+   * Must be `LJP_ABI_VERSION`
+   */
+  uint32_t abi_version;
+  /**
+   * `LJP_FLAG_*`, the directives of the file header are applied on top of them
+   */
+  uint32_t flags;
+  /**
+   * `LJP_FRONTEND_*`, the file header takes precedence
+   */
+  uint32_t frontend;
+  /**
+   * `LJP_CACHE_*`
+   */
+  uint32_t cache_policy;
+  /**
+   * NUL-terminated cache directory, NULL disables the cache
+   */
+  const char *cache_dir;
+} ljp_options;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Version of the C ABI implemented by this library, compare it with `LJP_ABI_VERSION`.
+ */
+uint32_t ljp_version(void);
+
+/**
+ * Version of the luajit_pro_helper crate, e.g. `0.1.0`.
+ */
+const char *ljp_version_string(void);
+
+/**
+ * Transform the file at `path`. `options` may be NULL, see `ljp_options`.
+ * With NULL `options` the result is LuaJIT bytecode if enabled by the project config or `LJP_BYTECODE`.
+ * Never returns NULL, the result must be released by `ljp_free_result()`.
+ */
+ljp_result *ljp_transform_file(const char *path, const ljp_options *options);
+
+/**
+ * Transform the `size` bytes of `buf`, `chunk_name` follows the LuaJIT convention(`@file`, `=name` or the code itself).
+ * With NULL `options` a chunk without the luajit-pro header is returned unchanged.
+ * Never returns NULL, the result must be released by `ljp_free_result()`.
+ */
+ljp_result *ljp_transform_buffer(const char *chunk_name,
+                                 const char *buf,
+                                 size_t size,
+                                 const ljp_options *options);
+
+/**
+ * Whether the transformation succeeded.
+ */
+bool ljp_result_ok(const ljp_result *result);
+
+/**
+ * The generated code or bytecode(NUL-terminated), its length is written into `size` if it is not NULL.
+ * Returns NULL if the transformation failed. Valid until the result is released.
+ */
+const char *ljp_result_code(const ljp_result *result, size_t *size);
+
+/**
+ * The full error message, e.g. `foo.lua:12: [luajit-pro comp_time] ...`.
+ * Returns NULL if the transformation succeeded. Valid until the result is released.
+ */
+const char *ljp_result_error(const ljp_result *result);
+
+/**
+ * The file the error was raised in, NULL if the transformation succeeded.
+ */
+const char *ljp_result_error_file(const ljp_result *result);
+
+/**
+ * The 1-based line of the error, 0 if unknown or if the transformation succeeded.
+ */
+size_t ljp_result_error_line(const ljp_result *result);
+
+/**
+ * The 1-based column of the error, 0 if unknown or if the transformation succeeded.
+ */
+size_t ljp_result_error_column(const ljp_result *result);
+
+/**
+ * The error code(e.g. `LJP0201`), NULL if the error has none or if the transformation succeeded.
+ */
+const char *ljp_result_error_code(const ljp_result *result);
+
+/**
+ * A hint on how to fix the error, NULL if the error has none or if the transformation succeeded.
+ */
+const char *ljp_result_error_hint(const ljp_result *result);
+
+/**
+ * The stage the error was raised in(e.g. `parse`, `comp_time`), NULL if the transformation succeeded.
+ */
+const char *ljp_result_error_stage(const ljp_result *result);
+
+/**
+ * Release a result returned by `ljp_transform_file()` or `ljp_transform_buffer()`, NULL is ignored.
+ */
+void ljp_free_result(ljp_result *result);
+
+/**
+ * Number of loads served by the build cache in this process.
+ */
+uint64_t ljp_cache_hits(void);
+
+/**
+ * Number of loads in this process that had to transform the source(missing, stale or corrupted cache entry).
+ */
+uint64_t ljp_cache_misses(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* LUAJIT_PRO_H */
@@ -26,6 +26,7 @@
 #ifdef LUAJIT_SYNTAX_EXTEND
 #include <assert.h>
 #include <stdlib.h>
+#include "luajit_pro.h"
 
 #define PURPLE_COLOR "\033[35m"
 #define RESET_COLOR "\033[0m"
@@ -57,14 +58,12 @@
         }                                                                                                                                                                                                                                                                                                                                                                                                      \
     } while (0)
 
-char *ljp_file_transform(const char *filename);
-void ljp_string_transform(const char *str, size_t *output_size);
+int ljp_string_has_marker(const char *str, size_t size);
+/* Loader internals of luajit_pro_helper(src/lib.rs, src/capi.rs), not part of luajit_pro.h */
+char ljp_file_matches_config(const char *file_path);
+ljp_result *ljp_load_file(const char *path, bool allow_bytecode);
 void luaL_openlibs(lua_State *L);
 
-void ljp_string_file_reset_ptr(const char *filename);
-size_t ljp_string_file_get_content(char *buf, size_t expectSize, const char *filename);
-char ljp_string_file_check_eof(const char *filename);
-
 #endif // LUAJIT_SYNTAX_EXTEND
 
 /* -- Load Lua source code and bytecode ----------------------------------- */
@@ -129,7 +128,8 @@ typedef struct FileReaderCtx {
 #ifdef LUAJIT_SYNTAX_EXTEND
   char filename[256]; /* Max 255 + 1 for null terminator. */
   unsigned char is_first_access;
-  unsigned char transformed;
+  const char *mode; /* The `mode` of loadfile(), the bytecode is only handed over if it allows "b". */
+  ljp_result *result; /* Owned by the loader, released by ljp_free_result() once the chunk is loaded. */
 #endif // LUAJIT_SYNTAX_EXTEND
   FILE *fp;
   char buf[LUAL_BUFFERSIZE];
@@ -138,17 +138,15 @@ typedef struct FileReaderCtx {
 static const char *reader_file(lua_State *L, void *ud, size_t *size)
 {
   FileReaderCtx *ctx = (FileReaderCtx *)ud;
+#ifndef LUAJIT_SYNTAX_EXTEND
   UNUSED(L);
+#endif
 
 #ifdef LUAJIT_SYNTAX_EXTEND
-  if(ctx->transformed) {
-    if (ljp_string_file_check_eof(ctx->filename)) return NULL;
-  } else {
-    if (feof(ctx->fp)) return NULL;
-  }
-#else
-  if (feof(ctx->fp)) return NULL;
+  // The transformed code is handed over as a whole by the first read
+  if(ctx->result != NULL) return NULL;
 #endif
+  if (feof(ctx->fp)) return NULL;
 
 #ifdef LUAJIT_SYNTAX_EXTEND
   if(ctx->is_first_access == 1) {
@@ -159,15 +157,27 @@ static const char *reader_file(lua_State *L, void *ud, size_t *size)
     static const char* substring = "luajit-pro";
 
     if (fgets(first_line_buffer, sizeof(first_line_buffer), ctx->fp) != NULL) {
-      if (strstr(first_line_buffer, substring) != NULL) {
-        char *new_file = ljp_file_transform(ctx->filename);
-        // printf("[Debug]new_file => %s\n", new_file);fflush(stdout);
-        if(new_file == NULL) {
-          LJP_WARNING("new_file is NULL! Cannot read file: %s, check if this file is empty. errno: %s\n", ctx->filename, strerror(errno));
-        } else {
-          ctx->transformed = 1;
-          ljp_string_file_reset_ptr(ctx->filename);
+      // The header may follow a UTF-8 BOM and a shebang line(any first line starting with `#`, which the lexer skips)
+      const char *first_line = first_line_buffer;
+      if (strncmp(first_line, "\xEF\xBB\xBF", 3) == 0) first_line += 3;
+      if (first_line[0] == '#') {
+        // Skip the rest of a long shebang line
+        while (strchr(first_line_buffer, '\n') == NULL && fgets(first_line_buffer, sizeof(first_line_buffer), ctx->fp) != NULL) {}
+        if (fgets(first_line_buffer, sizeof(first_line_buffer), ctx->fp) == NULL) first_line_buffer[0] = '\0';
+      }
+
+      // Only the marker is looked for, the header(which may span several lines) is parsed by luajit_pro_helper.
+      // Files matching a `[[files]]` entry of the project config(ljp.toml) are transformed without the header
+      if (strstr(first_line_buffer, substring) != NULL || ljp_file_matches_config(ctx->filename)) {
+        // With a text-only mode(`loadfile(path, "t")`) the generated code is loaded instead of the cached bytecode
+        ctx->result = ljp_load_file(ctx->filename, ctx->mode == NULL || strchr(ctx->mode, 'b') != NULL);
+        if(!ljp_result_ok(ctx->result)) {
+          // Surface the transform error as a normal syntax error so that `loadfile`/`pcall(require, ...)` can handle it.
+          lua_pushstring(L, ljp_result_error(ctx->result));
+          lj_err_throw(L, LUA_ERRSYNTAX);
         }
+        const char *code = ljp_result_code(ctx->result, size);
+        return *size > 0 ? code : NULL;
       } else {
         // The read file did not contains "--[[luajit-pro]]"
       }
@@ -176,15 +186,9 @@ static const char *reader_file(lua_State *L, void *ud, size_t *size)
       LJP_WARNING("Cannot read file: %s, check if this file is empty. errno: %s\n", ctx->filename, strerror(errno));
     }
   }
+#endif // LUAJIT_SYNTAX_EXTEND
 
-  if(ctx->transformed == 1) {
-    *size = ljp_string_file_get_content(ctx->buf, sizeof(ctx->buf), ctx->filename);
-  } else {
-    *size = fread(ctx->buf, 1, sizeof(ctx->buf), ctx->fp);
-  }
-#else
   *size = fread(ctx->buf, 1, sizeof(ctx->buf), ctx->fp);
-#endif // LUAJIT_SYNTAX_EXTEND
 
   return *size > 0 ? ctx->buf : NULL;
 }
@@ -304,10 +308,14 @@ LUALIB_API int luaL_loadfilex(lua_State *L, const char *filename,
 
   // A flag that indicates whether it is the first access to the file.
   ctx.is_first_access = 1;
-  ctx.transformed = 0;
+  ctx.mode = mode;
+  ctx.result = NULL;
 #endif // LUAJIT_SYNTAX_EXTEND
 
   status = lua_loadx(L, reader_file, &ctx, chunkname, mode);
+#ifdef LUAJIT_SYNTAX_EXTEND
+  ljp_free_result(ctx.result);
+#endif // LUAJIT_SYNTAX_EXTEND
   if (ferror(ctx.fp)) {
     L->top -= filename ? 2 : 1;
     lua_pushfstring(L, "cannot read %s: %s", chunkname+1, strerror(errno));
@@ -331,20 +339,39 @@ LUALIB_API int luaL_loadfile(lua_State *L, const char *filename)
 typedef struct StringReaderCtx {
   const char *str;
   size_t size;
+#ifdef LUAJIT_SYNTAX_EXTEND
+  const char *name;
+  ljp_result *result; /* Owned by the loader, released by ljp_free_result() once the chunk is loaded. */
+  unsigned char is_first_access;
+#endif // LUAJIT_SYNTAX_EXTEND
 } StringReaderCtx;
 
 static const char *reader_string(lua_State *L, void *ud, size_t *size)
 {
   StringReaderCtx *ctx = (StringReaderCtx *)ud;
+#ifndef LUAJIT_SYNTAX_EXTEND
   UNUSED(L);
+#endif
   if (ctx->size == 0) return NULL;
-  *size = ctx->size;
-  ctx->size = 0;
 
 #ifdef LUAJIT_SYNTAX_EXTEND
-  ljp_string_transform(ctx->str, size);
+  if(ctx->is_first_access == 1) {
+    ctx->is_first_access = 0;
+
+    if(ljp_string_has_marker(ctx->str, ctx->size)) {
+      ctx->result = ljp_transform_buffer(ctx->name, ctx->str, ctx->size, NULL);
+      if(!ljp_result_ok(ctx->result)) {
+        lua_pushstring(L, ljp_result_error(ctx->result));
+        lj_err_throw(L, LUA_ERRSYNTAX);
+      }
+      ctx->str = ljp_result_code(ctx->result, &ctx->size);
+      if (ctx->size == 0) return NULL;
+    }
+  }
 #endif // LUAJIT_SYNTAX_EXTEND
 
+  *size = ctx->size;
+  ctx->size = 0;
   return ctx->str;
 }
 
@@ -352,9 +379,21 @@ LUALIB_API int luaL_loadbufferx(lua_State *L, const char *buf, size_t size,
 				const char *name, const char *mode)
 {
   StringReaderCtx ctx;
+#ifdef LUAJIT_SYNTAX_EXTEND
+  int status;
+#endif
   ctx.str = buf;
   ctx.size = size;
+#ifdef LUAJIT_SYNTAX_EXTEND
+  ctx.name = name ? name : "?";
+  ctx.result = NULL;
+  ctx.is_first_access = 1;
+  status = lua_loadx(L, reader_string, &ctx, name, mode);
+  ljp_free_result(ctx.result);
+  return status;
+#else
   return lua_loadx(L, reader_string, &ctx, name, mode);
+#endif // LUAJIT_SYNTAX_EXTEND
 }
 
 LUALIB_API int luaL_loadbuffer(lua_State *L, const char *buf, size_t size,
@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::batch::{self, SOURCE_EXTENSIONS};
+use crate::directives;
+use crate::error::{self, TransformError, TransformStage};
+
+#[derive(Debug, Default)]
+pub struct AotReport {
+    /// Transformed files as (source, output), `.tl`/`.luau` sources are written as `.lua`
+    pub transformed: Vec<(String, String)>,
+    /// Files copied unchanged, relative to the source root
+    pub copied: Vec<String>,
+    /// Errors of the failed files, sorted by file
+    pub errors: Vec<TransformError>,
+}
+
+fn visit_dir(root: &Path, dir: &Path, skip: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Skip hidden entries(e.g. `.git`, `.luajit_pro`) and the output root if it is inside of the source root
+        if entry.file_name().to_string_lossy().starts_with('.')
+            || std::fs::canonicalize(&path).is_ok_and(|p| p == skip)
+        {
+            continue;
+        }
+        if path.is_dir() {
+            visit_dir(root, &path, skip, files);
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push(relative.to_path_buf());
+        }
+    }
+}
+
+fn transform_file(source: &Path, output: &Path) -> Result<(), TransformError> {
+    let source_path = source.to_string_lossy();
+    let code = crate::expand_lua_file(&source_path)?;
+    // Stock LuaJIT does not need the header, the comp-time code is already resolved
+    let code = directives::strip_header(&code);
+    error::catch(&source_path, || {
+        error::set_stage(TransformStage::Cache);
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent).expect("Failed to create output directory");
+        }
+        std::fs::write(output, code).expect("Failed to write output file");
+    })
+}
+
+/// Build the tree under `src_root` into `out_root` for an unpatched LuaJIT on `jobs` worker threads(the number of CPUs if 0).
+///
+/// Every file the loader would transform is written as plain Lua at the same relative path,
+/// everything else is copied unchanged. The build cache is neither read nor written.
+pub fn build_tree(src_root: &str, out_root: &str, jobs: usize) -> AotReport {
+    let src_root = Path::new(src_root);
+    let out_root = Path::new(out_root);
+    let _ = std::fs::create_dir_all(out_root);
+    let skip = std::fs::canonicalize(out_root).unwrap_or_else(|_| out_root.to_path_buf());
+
+    let mut files = Vec::new();
+    visit_dir(src_root, src_root, &skip, &mut files);
+    files.sort();
+
+    let report = Mutex::new(AotReport::default());
+    batch::for_each_parallel(&files, jobs, |relative| {
+        let source = src_root.join(relative);
+        let is_source = relative
+            .extension()
+            .is_some_and(|ext| SOURCE_EXTENSIONS.contains(&ext.to_string_lossy().as_ref()));
+
+        if is_source && batch::is_loader_transformed(&source) {
+            let output = out_root.join(relative).with_extension("lua");
+            let ret = transform_file(&source, &output);
+            let mut report = report.lock().unwrap();
+            match ret {
+                Ok(()) => report.transformed.push((
+                    source.to_string_lossy().into_owned(),
+                    output.to_string_lossy().into_owned(),
+                )),
+                Err(e) => report.errors.push(e),
+            }
+            return;
+        }
+
+        let output = out_root.join(relative);
+        let ret = output
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::copy(&source, &output));
+        let mut report = report.lock().unwrap();
+        match ret {
+            Ok(_) => report.copied.push(relative.to_string_lossy().into_owned()),
+            Err(e) => report.errors.push(TransformError::new(
+                TransformStage::Read,
+                &source.to_string_lossy(),
+                format!("Failed to copy to {} => {}", output.display(), e),
+            )),
+        }
+    });
+
+    let mut report = report.into_inner().unwrap();
+    report.transformed.sort();
+    report.copied.sort();
+    report.errors.sort_by(|a, b| a.file.cmp(&b.file));
+    report
+}
@@ -4,12 +4,17 @@ use full_moon::{
     ast::{
         punctuated::{Pair, Punctuated},
         span::ContainedSpan,
-        Call, Expression, FunctionArgs, FunctionCall, Index, Prefix, Suffix, Var, VarExpression,
+        Call, Expression, FunctionArgs, FunctionCall, Index, Prefix, Stmt, Suffix, Var,
+        VarExpression,
     },
+    node::Node,
     tokenizer::{Token, TokenReference, TokenType},
+    visitors::Visitor,
     ShortString,
 };
 
+use crate::error::{self, TransformStage};
+
 pub fn empty_token(token_ref: &TokenReference) -> TokenReference {
     token_ref.with_token(Token::new(TokenType::Whitespace {
         characters: ShortString::new(""),
@@ -368,3 +373,42 @@ pub fn get_func_call_name(func_call: &FunctionCall) -> (String, String) {
 
     (func_call_name, func_call_arg)
 }
+
+/// Parse `code` into an AST, parse failures are raised as `TransformError`s pointing at the first error.
+pub fn parse_lua(code: &str) -> full_moon::ast::Ast {
+    match full_moon::parse(code) {
+        Ok(ast) => ast,
+        Err(errors) => {
+            let position = errors.first().map(|e| e.range().0);
+            let message = errors
+                .iter()
+                .map(|e| e.error_message().to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            error::Report::new(message)
+                .stage(TransformStage::Parse)
+                .code(error::E_PARSE)
+                .at(position.map(|p| p.line()), position.map(|p| p.character()))
+                .raise()
+        }
+    }
+}
+
+/// Lines on which a statement of `ast` starts, ascending and without duplicates.
+pub fn statement_lines(ast: &full_moon::ast::Ast) -> Vec<usize> {
+    struct StatementLines(Vec<usize>);
+
+    impl Visitor for StatementLines {
+        fn visit_stmt(&mut self, node: &Stmt) {
+            if let Some(position) = node.start_position() {
+                self.0.push(position.line());
+            }
+        }
+    }
+
+    let mut visitor = StatementLines(Vec::new());
+    visitor.visit_ast(ast);
+    visitor.0.sort_unstable();
+    visitor.0.dedup();
+    visitor.0
+}
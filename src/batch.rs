@@ -0,0 +1,117 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::directives;
+use crate::error::{self, TransformError};
+
+/// Extensions of the files picked up by `collect_files()`
+pub const SOURCE_EXTENSIONS: [&str; 3] = ["lua", "tl", "luau"];
+
+// Comp-time code can recurse deeply, use the same stack size as the main thread
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// Number of files that were transformed(or already cached)
+    pub transformed: usize,
+    /// Errors of the failed files, sorted by file
+    pub errors: Vec<TransformError>,
+    pub elapsed: Duration,
+}
+
+pub(crate) fn is_loader_transformed(path: &Path) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    // The header may follow a shebang line
+    let mut reader = BufReader::new(file);
+    let mut first_lines = String::new();
+    for _ in 0..2 {
+        if reader.read_line(&mut first_lines).is_err() {
+            return false;
+        }
+    }
+    directives::starts_with_header(&first_lines)
+        || crate::file_matches_config(&path.to_string_lossy())
+}
+
+fn visit_dir(dir: &Path, files: &mut Vec<String>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Skip hidden directories, e.g. `.git` and the build cache in `.luajit_pro`
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            visit_dir(&path, files);
+        } else if path
+            .extension()
+            .is_some_and(|ext| SOURCE_EXTENSIONS.contains(&ext.to_string_lossy().as_ref()))
+            && is_loader_transformed(&path)
+        {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// The files under `root` which the LuaJIT loader would transform, i.e. the ones with the luajit-pro header
+/// or matching a `[[files]]` entry of their project config.
+pub fn collect_files(root: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    visit_dir(Path::new(root), &mut files);
+    files.sort();
+    files
+}
+
+/// Run `f` on each item on `jobs` worker threads(the number of CPUs if 0).
+pub(crate) fn for_each_parallel<T: Sync>(items: &[T], jobs: usize, f: impl Fn(&T) + Sync) {
+    let jobs = if jobs == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        jobs
+    };
+
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for i in 0..jobs.min(items.len()) {
+            std::thread::Builder::new()
+                .name(format!("ljp-worker-{i}"))
+                .stack_size(WORKER_STACK_SIZE)
+                .spawn_scoped(s, || {
+                    while let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        f(item);
+                    }
+                })
+                .expect("Failed to spawn worker thread");
+        }
+    });
+}
+
+/// Transform `files` on `jobs` worker threads(the number of CPUs if 0) and fill the build cache,
+/// so that the first `require` of each file is a cache hit.
+///
+/// The comp-time and Teal Lua states are thread locals, so every worker has its own ones.
+pub fn pregenerate(files: &[String], jobs: usize) -> BatchReport {
+    let start = Instant::now();
+
+    let errors = Mutex::new(Vec::new());
+    for_each_parallel(files, jobs, |file| {
+        if let Err(e) = error::catch(file, || crate::transform_lua_file(file)) {
+            errors.lock().unwrap().push(e);
+        }
+    });
+
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by(|a, b| a.file.cmp(&b.file));
+    BatchReport {
+        transformed: files.len() - errors.len(),
+        errors,
+        elapsed: start.elapsed(),
+    }
+}
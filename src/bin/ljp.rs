@@ -0,0 +1,379 @@
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use luajit_pro_helper::{
+    BuildCache, CacheEntryStatus, Diagnostic, Directives, TransformError, WatchEvent, Watcher,
+};
+
+/// Command line interface of the luajit-pro transformer.
+#[derive(Parser)]
+#[command(name = "ljp", version, about)]
+struct Cli {
+    /// Format of the reported errors and warnings
+    #[arg(long, global = true, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the transformed code of a file to stdout
+    Expand { file: String },
+    /// Run the transformation of the files and report the errors
+    Check {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Show the directives and params parsed from the header of a file
+    Explain { file: String },
+    /// Transform every luajit-pro file under the directories in parallel and fill the build cache
+    Build {
+        #[arg(default_value = ".")]
+        dirs: Vec<String>,
+        /// Number of worker threads, defaults to the number of CPUs
+        #[arg(short, long, default_value_t = 0)]
+        jobs: usize,
+    },
+    /// Build a source tree into an output tree of plain Lua which runs on an unpatched LuaJIT
+    Aot {
+        src: String,
+        out: String,
+        /// Number of worker threads, defaults to the number of CPUs
+        #[arg(short, long, default_value_t = 0)]
+        jobs: usize,
+    },
+    /// Rebuild the files under the directories whenever they or their dependencies change
+    Watch {
+        #[arg(default_value = ".")]
+        dirs: Vec<String>,
+        /// Polling interval in milliseconds
+        #[arg(short, long, default_value_t = 500)]
+        interval: u64,
+    },
+    /// Inspect or clean the build cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List the cache entries
+    Ls {
+        /// Show the key, timestamps and dependencies of each entry
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Show the number of entries, their total size and how many are stale or orphaned
+    Stats,
+    /// Remove every cache entry
+    Clean,
+    /// Remove the entries whose source no longer exists
+    Orphans,
+    /// Remove the stale entries and the ones whose source no longer exists
+    Prune {
+        /// Also remove the entries not used for this long, e.g. `3600`, `90m`, `12h`, `30d`
+        #[arg(long, value_parser = parse_duration)]
+        max_age: Option<Duration>,
+        /// Then remove the least recently used entries until the cache fits, e.g. `4096`, `512K`, `100M`, `1G`
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+    /// Pack the cache files into a read-only bundle, see `cache_backend = "bundle"`
+    Bundle {
+        /// Path of the bundle
+        out: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MessageFormat {
+    /// Annotated source snippets
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+impl MessageFormat {
+    fn error(self, e: &TransformError) {
+        match self {
+            MessageFormat::Human => eprintln!("{}", e.render()),
+            MessageFormat::Json => eprintln!("{}", e.to_json()),
+        }
+    }
+
+    fn diagnostic(self, diagnostic: &Diagnostic) {
+        match self {
+            MessageFormat::Human => eprintln!("{}", diagnostic.render()),
+            MessageFormat::Json => eprintln!("{}", diagnostic.to_json()),
+        }
+    }
+}
+
+/// Seconds, or a number followed by `s`, `m`, `h` or `d`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, unit @ ('s' | 'm' | 'h' | 'd'))) => (&value[..i], unit),
+        _ => (value, 's'),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{value}`"))?;
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        _ => 24 * 60 * 60,
+    };
+    Ok(Duration::from_secs(number * scale))
+}
+
+/// Bytes, or a number followed by `K`, `M` or `G`(powers of 1024).
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, unit @ ('K' | 'M' | 'G'))) => (&value[..i], unit),
+        _ => (value, 'B'),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{value}`"))?;
+    let scale = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        _ => 1 << 30,
+    };
+    Ok(number * scale)
+}
+
+fn ago(time: Option<SystemTime>) -> String {
+    let Some(time) = time else {
+        return "never".to_string();
+    };
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let format = cli.message_format;
+
+    match cli.command {
+        Command::Expand { file } => match luajit_pro_helper::expand_lua_file(&file) {
+            Ok(code) => {
+                print!("{code}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                format.error(&e);
+                ExitCode::FAILURE
+            }
+        },
+        Command::Check { files } => {
+            let mut failed = 0;
+            for file in &files {
+                if let Err(e) = luajit_pro_helper::expand_lua_file(file) {
+                    format.error(&e);
+                    failed += 1;
+                }
+            }
+            println!("{} file(s) checked, {} failed", files.len(), failed);
+            if failed == 0 {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Command::Explain { file } => {
+            let content = match std::fs::read_to_string(&file) {
+                Ok(content) => content,
+                Err(e) => {
+                    eprintln!("Failed to read {file}: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let start = luajit_pro_helper::header_start(&content);
+            let header = match luajit_pro_helper::header_end(&content) {
+                Some(end) => &content[start..end],
+                None => content[start..].lines().next().unwrap_or(""),
+            };
+            if matches!(Directives::parse(header), Ok(None))
+                && !luajit_pro_helper::file_matches_config(&file)
+            {
+                println!("{file}: no luajit-pro header, the file is loaded as is");
+                return ExitCode::SUCCESS;
+            }
+
+            let (directives, params) = match luajit_pro_helper::explain_header(&file, header) {
+                Ok(explained) => explained,
+                Err(e) => {
+                    format.error(&e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            println!("header: {}", header.trim_end());
+            println!("directives:");
+            for directive in directives {
+                println!("    {directive}");
+            }
+            println!("params:");
+            for (key, value) in params {
+                println!("    {key} = {value}");
+            }
+            ExitCode::SUCCESS
+        }
+        Command::Build { dirs, jobs } => {
+            let files: Vec<String> = dirs
+                .iter()
+                .flat_map(|dir| luajit_pro_helper::collect_files(dir))
+                .collect();
+            let report = luajit_pro_helper::pregenerate(&files, jobs);
+            for e in &report.errors {
+                format.error(e);
+            }
+            let stats = luajit_pro_helper::cache_stats();
+            println!(
+                "{} file(s) built, {} failed in {:.2?} (cache: {} hit(s), {} miss(es))",
+                report.transformed,
+                report.errors.len(),
+                report.elapsed,
+                stats.hits,
+                stats.misses
+            );
+            if report.errors.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Command::Aot { src, out, jobs } => {
+            let report = luajit_pro_helper::build_tree(&src, &out, jobs);
+            for file in &report.copied {
+                println!("copied unchanged: {file}");
+            }
+            for e in &report.errors {
+                format.error(e);
+            }
+            println!(
+                "{} file(s) transformed, {} copied unchanged, {} failed",
+                report.transformed.len(),
+                report.copied.len(),
+                report.errors.len()
+            );
+            if report.errors.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Command::Watch { dirs, interval } => {
+            println!("watching {}", dirs.join(", "));
+            Watcher::new(&dirs).run(Duration::from_millis(interval), |event| match event {
+                WatchEvent::Built { file, diagnostics } => {
+                    for diagnostic in &diagnostics {
+                        format.diagnostic(diagnostic);
+                    }
+                    println!("built {file}");
+                }
+                WatchEvent::Failed(e) => format.error(&e),
+                WatchEvent::Removed(file) => println!("removed {file}"),
+            })
+        }
+        Command::Cache { command } => {
+            let cache = BuildCache::current();
+            match command {
+                CacheCommand::Ls { verbose } => {
+                    println!("cache dir: {}", cache.dir);
+                    println!("backend: {}", cache.backend.name());
+                    for entry in cache.entries() {
+                        let status = match (entry.orphan, entry.fresh) {
+                            (true, _) => "orphan",
+                            (false, true) => "fresh",
+                            (false, false) => "stale",
+                        };
+                        println!(
+                            "{status:<6} {:>8}B {} => {}",
+                            entry.size, entry.source, entry.entry
+                        );
+                        if verbose {
+                            println!("    key: {}", entry.key);
+                            println!(
+                                "    created: {}, last hit: {}",
+                                ago(entry.created),
+                                ago(entry.last_hit)
+                            );
+                            for dep in &entry.deps {
+                                println!("    dep: {dep}");
+                            }
+                        }
+                    }
+                    ExitCode::SUCCESS
+                }
+                CacheCommand::Stats => {
+                    let entries = cache.entries();
+                    let count = |f: fn(&CacheEntryStatus) -> bool| {
+                        entries.iter().filter(|entry| f(entry)).count()
+                    };
+                    println!("cache dir: {}", cache.dir);
+                    println!("backend: {}", cache.backend.name());
+                    println!("entries: {}", entries.len());
+                    println!(
+                        "size: {}B",
+                        entries.iter().map(|entry| entry.size).sum::<u64>()
+                    );
+                    println!("fresh: {}", count(|entry| entry.fresh));
+                    println!("stale: {}", count(|entry| !entry.fresh && !entry.orphan));
+                    println!("orphan: {}", count(|entry| entry.orphan));
+                    println!(
+                        "oldest use: {}",
+                        ago(entries.iter().filter_map(|entry| entry.last_used()).min())
+                    );
+                    ExitCode::SUCCESS
+                }
+                CacheCommand::Clean => match cache.clean() {
+                    Ok(count) => {
+                        println!("{count} file(s) removed");
+                        ExitCode::SUCCESS
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to clean {}: {e}", cache.dir);
+                        ExitCode::FAILURE
+                    }
+                },
+                CacheCommand::Orphans => {
+                    println!("{} entries removed", cache.remove_orphans());
+                    ExitCode::SUCCESS
+                }
+                CacheCommand::Prune { max_age, max_size } => {
+                    let mut count = cache.prune();
+                    if max_age.is_some() || max_size.is_some() {
+                        count += cache.prune_by(max_age, max_size);
+                    }
+                    println!("{count} entries removed");
+                    ExitCode::SUCCESS
+                }
+                CacheCommand::Bundle { out } => match cache.bundle(&out) {
+                    Ok(count) => {
+                        println!("{count} file(s) packed into {out}");
+                        ExitCode::SUCCESS
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to write {out}: {e}");
+                        ExitCode::FAILURE
+                    }
+                },
+            }
+        }
+    }
+}
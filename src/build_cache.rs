@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::cache::{self, CacheEntry};
+use crate::cache_backend::{self, CacheBackend};
+use crate::{config, error};
+
+/// Temp and lock files older than this are left behind by crashed processes
+const LEFTOVER_AGE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone)]
+pub struct CacheEntryStatus {
+    pub entry: String,
+    /// Canonical path of the source file
+    pub source: String,
+    /// Hash of everything the generated code depends on
+    pub key: String,
+    /// Total size of the entry and its side files(metadata, bytecode, source map)
+    pub size: u64,
+    /// `None` for the entries generated by older versions
+    pub created: Option<SystemTime>,
+    /// Last load served by the entry(with a resolution of a minute), `None` if it never was
+    pub last_hit: Option<SystemTime>,
+    /// Files the entry was generated from besides the source
+    pub deps: Vec<String>,
+    /// Whether the entry would be used for the current source, params and env
+    pub fresh: bool,
+    /// The source no longer exists
+    pub orphan: bool,
+}
+
+impl CacheEntryStatus {
+    /// The last hit, or the creation if the entry was never loaded.
+    pub fn last_used(&self) -> Option<SystemTime> {
+        self.last_hit.or(self.created)
+    }
+}
+
+fn from_unix(secs: u64) -> Option<SystemTime> {
+    (secs > 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn is_fresh(backend: &dyn CacheBackend, entry: &CacheEntry) -> bool {
+    let Ok(content) = std::fs::read_to_string(&entry.meta.source) else {
+        return false;
+    };
+    error::catch(&entry.meta.source, || {
+        let pipeline = crate::legacy_pipeline();
+        let settings = pipeline.resolve(&content, &entry.meta.source);
+        let bytecode = crate::bytecode_strip(settings.config.as_deref());
+        let key = crate::cache_key(&pipeline, &entry.meta.source, &content, &settings, bytecode);
+        cache::is_valid(backend, &entry.entry, &key)
+    })
+    .unwrap_or(false)
+}
+
+/// The build cache of a project: its directory and backend, see `BuildCache::current()`.
+///
+/// The metadata of the entries is the index of the cache, every listing reads it again so that entries
+/// written by other processes are seen.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    pub dir: String,
+    pub backend: Arc<dyn CacheBackend>,
+}
+
+impl BuildCache {
+    pub fn new(dir: impl Into<String>, backend: Arc<dyn CacheBackend>) -> Self {
+        BuildCache {
+            dir: dir.into(),
+            backend,
+        }
+    }
+
+    /// The cache the loader uses for `file_path`(a source file or a directory of the project).
+    pub fn of(file_path: &str) -> Self {
+        let config = config::project_config(file_path).ok().flatten();
+        BuildCache {
+            dir: crate::cache_dir_for(config.as_deref(), file_path),
+            backend: crate::cache_backend_for(config.as_deref()),
+        }
+    }
+
+    /// The cache of the project in the current directory.
+    pub fn current() -> Self {
+        BuildCache::of(".")
+    }
+
+    /// Every entry with its metadata, sorted by source path.
+    pub fn entries(&self) -> Vec<CacheEntryStatus> {
+        let backend = self.backend.as_ref();
+        cache::list_entries(backend, &self.dir)
+            .into_iter()
+            .map(|entry| CacheEntryStatus {
+                fresh: is_fresh(backend, &entry),
+                orphan: !std::fs::exists(&entry.meta.source).unwrap_or(false),
+                created: from_unix(entry.meta.created),
+                last_hit: entry.meta.last_hit.and_then(from_unix),
+                deps: entry.meta.deps.into_iter().map(|dep| dep.path).collect(),
+                key: entry.meta.key,
+                source: entry.meta.source,
+                size: entry.size,
+                entry: entry.entry,
+            })
+            .collect()
+    }
+
+    /// Remove every file of the cache, returns the number of removed files.
+    pub fn clean(&self) -> std::io::Result<usize> {
+        if self.backend.read_only() {
+            return Ok(0);
+        }
+        cache::clean(self.backend.as_ref(), &self.dir)
+    }
+
+    fn remove_where(&self, f: impl Fn(&CacheEntryStatus) -> bool) -> usize {
+        if self.backend.read_only() {
+            return 0;
+        }
+        let mut count = 0;
+        for entry in self.entries().iter().filter(|entry| f(entry)) {
+            cache::remove_entry(self.backend.as_ref(), &entry.entry);
+            count += 1;
+        }
+        count
+    }
+
+    /// Remove the entries whose source no longer exists, returns the number of removed entries.
+    pub fn remove_orphans(&self) -> usize {
+        self.remove_where(|entry| entry.orphan)
+    }
+
+    /// Remove the stale entries and the ones whose source no longer exists, returns the number of removed entries.
+    /// Temp and lock files left behind by crashed processes are removed as well.
+    pub fn prune(&self) -> usize {
+        if self.backend.read_only() {
+            return 0;
+        }
+        cache::remove_leftovers(&self.dir, LEFTOVER_AGE);
+        self.remove_where(|entry| !entry.fresh)
+    }
+
+    /// Remove the entries not used for `max_age`, then the least recently used ones until the cache takes
+    /// at most `max_size` bytes. Returns the number of removed entries.
+    pub fn prune_by(&self, max_age: Option<Duration>, max_size: Option<u64>) -> usize {
+        if self.backend.read_only() {
+            return 0;
+        }
+        let now = SystemTime::now();
+        let is_old = |entry: &CacheEntryStatus| match (max_age, entry.last_used()) {
+            (Some(max_age), Some(last_used)) => {
+                now.duration_since(last_used).unwrap_or_default() > max_age
+            }
+            // Entries of older versions have no timestamps
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let mut count = self.remove_where(is_old);
+
+        let Some(max_size) = max_size else {
+            return count;
+        };
+        let mut entries = self.entries();
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        entries.sort_by_key(|entry| entry.last_used());
+        for entry in entries {
+            if size <= max_size {
+                break;
+            }
+            cache::remove_entry(self.backend.as_ref(), &entry.entry);
+            size -= entry.size;
+            count += 1;
+        }
+        count
+    }
+
+    /// Pack the cache into the read-only bundle `out`, see `cache_backend = "bundle"` of ljp.toml.
+    /// Returns the number of packed files.
+    pub fn bundle(&self, out: &str) -> std::io::Result<usize> {
+        cache_backend::write_bundle(self.backend.as_ref(), &self.dir, out)
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use fslock::LockFile;
+use serde::{Deserialize, Serialize};
+
+use crate::cache_backend::CacheBackend;
+use crate::deps::Tracked;
+use crate::directives::ParamValue;
+
+const HELPER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `last_hit` is only rewritten once it is older than this, so that hot entries are not rewritten on every load
+const LAST_HIT_RESOLUTION: u64 = 60;
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Metadata stored next to each cache entry(`<entry>.meta`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheMeta {
+    /// Canonical path of the source file
+    pub source: String,
+    /// Hash of everything the generated code depends on, see `compute_key()`
+    pub key: String,
+    pub version: String,
+    /// Hash of the cached code, checked on every read so that a truncated entry is never used
+    #[serde(default)]
+    pub checksum: String,
+    /// Files read while generating the entry, the entry is stale once any of them changes
+    #[serde(default)]
+    pub deps: Vec<Dependency>,
+    /// Env vars read at comp-time and their values(`None` if unset), the entry is stale once any of them differs
+    #[serde(default)]
+    pub env: BTreeMap<String, Option<String>>,
+    /// When the entry was generated, in seconds since the Unix epoch(0 for entries of older versions)
+    #[serde(default)]
+    pub created: u64,
+    /// When the entry was last loaded(see `LAST_HIT_RESOLUTION`), `None` if it never was
+    #[serde(default)]
+    pub last_hit: Option<u64>,
+}
+
+/// Hits and misses of the build cache in this process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Loads served by a valid entry
+    pub hits: u64,
+    /// Loads that had to transform the source, because the entry was missing, stale or corrupted
+    pub misses: u64,
+}
+
+pub fn stats() -> CacheStats {
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Count a load that had to transform the source, hits are counted by `load()`.
+pub fn record_miss() {
+    MISSES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dependency {
+    pub path: String,
+    /// Content hash, empty if the file could not be read
+    pub hash: String,
+}
+
+impl Dependency {
+    pub fn new(file_path: &str) -> Self {
+        Dependency {
+            path: file_path.to_string(),
+            hash: hash_file(file_path).unwrap_or_default(),
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        hash_file(&self.path).unwrap_or_default() != self.hash
+    }
+}
+
+pub fn hash_file(file_path: &str) -> Option<String> {
+    let content = std::fs::read(file_path).ok()?;
+    Some(blake3::hash(&content).to_hex().to_string())
+}
+
+pub fn canonical_path(file_path: &str) -> String {
+    std::fs::canonicalize(file_path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or(file_path.to_string())
+}
+
+/// Cache entries are named `<file name>.<hash of the canonical path>.lua`,
+/// so `a/init.lua` and `b/init.lua` never share an entry.
+pub fn entry_path(cache_dir: &str, file_path: &str) -> String {
+    let path_hash = blake3::hash(canonical_path(file_path).as_bytes()).to_hex();
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}/{}.{}.lua", cache_dir, file_name, &path_hash[..16])
+}
+
+#[inline]
+pub fn meta_path(entry_path: &str) -> String {
+    format!("{}.meta", entry_path)
+}
+
+#[inline]
+pub fn bytecode_path(entry_path: &str) -> String {
+    format!("{}.luac", entry_path)
+}
+
+/// Hash the source path and content, the injected param values, the flags that change the output
+/// (e.g. `LJP_NO_OPT`, the options of a `Pipeline`) and the helper version.
+pub fn compute_key(
+    file_path: &str,
+    source: &str,
+    param_table: &Option<HashMap<&str, ParamValue>>,
+    flags: &[(&str, String)],
+) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(HELPER_VERSION.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(canonical_path(file_path).as_bytes());
+    hasher.update(b"\0");
+    hasher.update(source.as_bytes());
+    hasher.update(b"\0");
+
+    if let Some(param_table) = param_table {
+        let mut params: Vec<_> = param_table.iter().collect();
+        params.sort_by_key(|(key, _)| **key);
+        for (key, value) in params {
+            hasher.update(format!("{key}={value}\0").as_bytes());
+        }
+    }
+
+    for (name, value) in flags {
+        hasher.update(format!("{name}={value}\0").as_bytes());
+    }
+
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Write `content` to a temp file next to `path` and rename it over `path`, so that readers see either
+/// the old or the new file but never a partial one.
+///
+/// The temp file is not synced, a file left truncated by a system crash is caught by the checksum instead.
+pub fn write_atomic(path: &str, content: impl AsRef<[u8]>) -> std::io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp_path = format!(
+        "{path}.{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let ret = std::fs::File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(content.as_ref()))
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    ret
+}
+
+/// Exclusive lock of a cache entry, see `lock()`.
+pub struct EntryLock {
+    lockfile: LockFile,
+    path: String,
+}
+
+impl Drop for EntryLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so that the next process locks a fresh file
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.lockfile.unlock();
+    }
+}
+
+/// Lock the entry until the returned guard is dropped, so that concurrent processes do not generate it
+/// at the same time. The `<entry>.lock` file only exists while the entry is locked.
+///
+/// A process that opened the lock file right before it was removed may still get a lock alongside the
+/// next one, the entry is then generated twice, which only costs time as the writes are atomic.
+pub fn lock(entry_path: &str) -> std::io::Result<EntryLock> {
+    let path = format!("{}.lock", entry_path);
+    loop {
+        let mut lockfile = LockFile::open(path.as_str())?;
+        lockfile.lock()?;
+        // The previous owner removed the file while we were waiting for it
+        if std::fs::exists(&path).unwrap_or(false) {
+            return Ok(EntryLock { lockfile, path });
+        }
+    }
+}
+
+pub fn read_meta(backend: &dyn CacheBackend, entry_path: &str) -> Option<CacheMeta> {
+    let meta = backend.read(&meta_path(entry_path))?;
+    serde_json::from_slice(&meta).ok()
+}
+
+/// Check that the entry was generated with the same `key` and none of its dependencies changed.
+pub fn is_valid(backend: &dyn CacheBackend, entry_path: &str, key: &str) -> bool {
+    read_meta(backend, entry_path).is_some_and(|meta| is_valid_meta(entry_path, &meta, key))
+}
+
+#[cfg_attr(not(feature = "debug"), allow(unused_variables))]
+fn is_valid_meta(entry_path: &str, meta: &CacheMeta, key: &str) -> bool {
+    if meta.key != key || meta.version != HELPER_VERSION {
+        return false;
+    }
+    for dep in &meta.deps {
+        if dep.changed() {
+            #[cfg(feature = "debug")]
+            log::debug!(
+                "[cache::is_valid] <{entry_path}> dependency changed: {}",
+                dep.path
+            );
+
+            return false;
+        }
+    }
+    for (key, value) in &meta.env {
+        if &std::env::var(key).ok() != value {
+            #[cfg(feature = "debug")]
+            log::debug!("[cache::is_valid] <{entry_path}> env var changed: {key}");
+
+            return false;
+        }
+    }
+    true
+}
+
+/// Read the cached code if the entry is still valid for `key` and intact.
+pub fn load(backend: &dyn CacheBackend, entry_path: &str, key: &str) -> Option<String> {
+    let meta = read_meta(backend, entry_path)?;
+    if !is_valid_meta(entry_path, &meta, key) {
+        return None;
+    }
+    let code = String::from_utf8(backend.read(entry_path)?).ok()?;
+    if blake3::hash(code.as_bytes()).to_hex().as_str() != meta.checksum {
+        #[cfg(feature = "debug")]
+        log::debug!("[cache::load] <{entry_path}> checksum mismatch");
+
+        return None;
+    }
+
+    HITS.fetch_add(1, Ordering::Relaxed);
+    touch(backend, entry_path, meta);
+    Some(code)
+}
+
+/// Update `last_hit` of the entry. Racing with `store()` is harmless: metadata rewritten over a newer entry
+/// carries the checksum of the old code, so the entry is only regenerated.
+fn touch(backend: &dyn CacheBackend, entry_path: &str, mut meta: CacheMeta) {
+    let now = unix_now();
+    if backend.read_only() || meta.last_hit.is_some_and(|t| now < t + LAST_HIT_RESOLUTION) {
+        return;
+    }
+    meta.last_hit = Some(now);
+    if let Ok(meta) = serde_json::to_string(&meta) {
+        let _ = backend.write(&meta_path(entry_path), meta.as_bytes());
+    }
+}
+
+pub fn store(
+    backend: &dyn CacheBackend,
+    entry_path: &str,
+    source_path: &str,
+    key: &str,
+    tracked: &Tracked,
+    content: &str,
+) -> std::io::Result<()> {
+    let source = canonical_path(source_path);
+    let meta = CacheMeta {
+        deps: tracked
+            .files
+            .iter()
+            .filter(|dep| **dep != source)
+            .map(|dep| Dependency::new(dep))
+            .collect(),
+        env: tracked.env.clone(),
+        source,
+        key: key.to_string(),
+        version: HELPER_VERSION.to_string(),
+        checksum: blake3::hash(content.as_bytes()).to_hex().to_string(),
+        created: unix_now(),
+        last_hit: None,
+    };
+
+    // Drop the old metadata first so that a partially updated entry is never treated as valid
+    let _ = backend.remove(&meta_path(entry_path));
+    backend.write(entry_path, content.as_bytes())?;
+    backend.write(
+        &meta_path(entry_path),
+        serde_json::to_string(&meta)
+            .expect("Failed to serialize cache meta")
+            .as_bytes(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub entry: String,
+    pub meta: CacheMeta,
+    /// Total size of the entry and its side files
+    pub size: u64,
+}
+
+/// The files of an entry: the code, its metadata, bytecode, source map and lock file.
+fn entry_files(entry_path: &str) -> [String; 5] {
+    [
+        entry_path.to_string(),
+        meta_path(entry_path),
+        bytecode_path(entry_path),
+        format!("{}.map", entry_path),
+        format!("{}.lock", entry_path),
+    ]
+}
+
+/// All entries of `cache_dir` that have metadata.
+pub fn list_entries(backend: &dyn CacheBackend, cache_dir: &str) -> Vec<CacheEntry> {
+    let mut entries: Vec<CacheEntry> = backend
+        .list(cache_dir)
+        .into_iter()
+        .filter_map(|path| {
+            let entry = path.strip_suffix(".meta")?.to_string();
+            let meta = read_meta(backend, &entry)?;
+            let size = entry_files(&entry)
+                .iter()
+                .filter_map(|file| backend.size(file))
+                .sum();
+            Some(CacheEntry { entry, meta, size })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.meta.source.cmp(&b.meta.source));
+    entries
+}
+
+/// Remove an entry together with its metadata, bytecode, source map and lock file.
+pub fn remove_entry(backend: &dyn CacheBackend, entry_path: &str) {
+    for file in entry_files(entry_path) {
+        let _ = backend.remove(&file);
+    }
+}
+
+/// Remove the temp files and lock files older than `age` left behind by crashed processes(of the `fs` backend),
+/// returns the number of removed files.
+pub fn remove_leftovers(cache_dir: &str, age: Duration) -> usize {
+    let Ok(dir) = std::fs::read_dir(cache_dir) else {
+        return 0;
+    };
+
+    let now = SystemTime::now();
+    let mut count = 0;
+    for file in dir.filter_map(|f| f.ok()) {
+        let name = file.file_name().to_string_lossy().into_owned();
+        if !(name.ends_with(".tmp") || name.ends_with(".lock")) {
+            continue;
+        }
+        let old = file
+            .metadata()
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > age);
+        if old && std::fs::remove_file(file.path()).is_ok() {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Remove everything in `cache_dir`, returns the number of removed files.
+pub fn clean(backend: &dyn CacheBackend, cache_dir: &str) -> std::io::Result<usize> {
+    let mut count = 0;
+    for file in backend.list(cache_dir) {
+        backend.remove(&file)?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::cache::{self, EntryLock};
+
+const BUNDLE_MAGIC: &[u8] = b"LJPBUNDLE\x01";
+
+lazy_static! {
+    static ref MEMORY: Arc<MemoryBackend> = Arc::new(MemoryBackend::default());
+    // Opened bundles, a bundle is read once per process
+    static ref BUNDLES: Mutex<HashMap<PathBuf, Arc<BundleBackend>>> = Mutex::new(HashMap::new());
+}
+
+/// Storage of the build cache. Files are addressed by their path in the cache dir,
+/// e.g. `<cache dir>/main.lua.<hash>.lua` and its `.meta`, `.luac` and `.map` files.
+pub trait CacheBackend: Send + Sync + fmt::Debug {
+    /// Name shown by `ljp cache ls`
+    fn name(&self) -> &'static str;
+
+    fn read(&self, path: &str) -> Option<Vec<u8>>;
+
+    /// Replace the file as a whole, readers never see a partially written file.
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()>;
+
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /// Paths of the files in `dir`
+    fn list(&self, dir: &str) -> Vec<String>;
+
+    fn exists(&self, path: &str) -> bool {
+        self.read(path).is_some()
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        self.read(path).map(|content| content.len() as u64)
+    }
+
+    /// Lock the entry while it is generated, `None` if concurrent generation needs no lock.
+    fn lock(&self, _entry_path: &str) -> io::Result<Option<EntryLock>> {
+        Ok(None)
+    }
+
+    /// Read-only backends are never written, a missing or stale entry is transformed on every load.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// The cache files on disk, the default backend.
+#[derive(Debug, Default)]
+pub struct FsBackend;
+
+impl CacheBackend for FsBackend {
+    fn name(&self) -> &'static str {
+        "fs"
+    }
+
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        std::fs::read(path).ok()
+    }
+
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        cache::write_atomic(path, content)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|f| f.ok())
+            .filter(|f| f.path().is_file())
+            .map(|f| f.path().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        std::fs::exists(path).unwrap_or(false)
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        std::fs::metadata(path).map(|m| m.len()).ok()
+    }
+
+    fn lock(&self, entry_path: &str) -> io::Result<Option<EntryLock>> {
+        if let Some(dir) = Path::new(entry_path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        cache::lock(entry_path).map(Some)
+    }
+}
+
+/// Cache files kept in memory for the lifetime of the process, nothing is written to disk.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    /// The store shared by every user of the `memory` backend in this process.
+    pub fn global() -> Arc<MemoryBackend> {
+        MEMORY.clone()
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), content.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        self.files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| {
+                path.strip_prefix(&prefix)
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// A read-only archive of cache files(see `write_bundle()`), e.g. shipped with a release.
+///
+/// Files are looked up by file name whatever the cache dir is. The entry names and keys depend on the
+/// canonical path of the sources, so the bundle must be built from sources at the same location as
+/// the deployed ones.
+pub struct BundleBackend {
+    path: PathBuf,
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl fmt::Debug for BundleBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BundleBackend")
+            .field("path", &self.path)
+            .field("files", &self.files.len())
+            .finish()
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Read a length prefixed chunk of the bundle.
+fn read_chunk(reader: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    if len > reader.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (chunk, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(chunk.to_vec())
+}
+
+impl BundleBackend {
+    pub fn open(path: &Path) -> io::Result<BundleBackend> {
+        let content = std::fs::read(path)?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a cache bundle", path.display()),
+            )
+        };
+        let mut reader = content.strip_prefix(BUNDLE_MAGIC).ok_or_else(invalid)?;
+
+        let mut files = HashMap::new();
+        while !reader.is_empty() {
+            let name = String::from_utf8(read_chunk(&mut reader)?).map_err(|_| invalid())?;
+            files.insert(name, read_chunk(&mut reader)?);
+        }
+        Ok(BundleBackend {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    fn read_only_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("The cache bundle {} is read-only", self.path.display()),
+        )
+    }
+}
+
+impl CacheBackend for BundleBackend {
+    fn name(&self) -> &'static str {
+        "bundle"
+    }
+
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.get(file_name(path)).cloned()
+    }
+
+    fn write(&self, _path: &str, _content: &[u8]) -> io::Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn remove(&self, _path: &str) -> io::Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let mut names: Vec<&String> = self.files.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| format!("{}/{}", dir.trim_end_matches('/'), name))
+            .collect()
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Pack the cache files of `cache_dir`(except the lock and temp files) into the bundle `out`,
+/// returns the number of packed files.
+pub fn write_bundle(backend: &dyn CacheBackend, cache_dir: &str, out: &str) -> io::Result<usize> {
+    let mut bundle = BUNDLE_MAGIC.to_vec();
+    let mut count = 0;
+    let mut files = backend.list(cache_dir);
+    files.sort();
+    for file in files {
+        let name = file_name(&file);
+        if name.ends_with(".lock") || name.ends_with(".tmp") {
+            continue;
+        }
+        let Some(content) = backend.read(&file) else {
+            continue;
+        };
+        bundle.extend_from_slice(&(name.len() as u64).to_le_bytes());
+        bundle.extend_from_slice(name.as_bytes());
+        bundle.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        bundle.extend_from_slice(&content);
+        count += 1;
+    }
+    cache::write_atomic(out, bundle)?;
+    Ok(count)
+}
+
+/// The `cache_backend` of the project config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    #[default]
+    Fs,
+    Memory,
+    Bundle,
+}
+
+/// A backend to open, see `open()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendSpec {
+    Fs,
+    Memory,
+    Bundle(PathBuf),
+}
+
+impl BackendSpec {
+    /// Parse the value of `LJP_CACHE_BACKEND`: `fs`, `memory` or `bundle:<path>`.
+    pub fn parse(value: &str) -> Result<BackendSpec, String> {
+        match value {
+            "fs" => Ok(BackendSpec::Fs),
+            "memory" => Ok(BackendSpec::Memory),
+            _ => match value.strip_prefix("bundle:") {
+                Some(path) if !path.is_empty() => Ok(BackendSpec::Bundle(PathBuf::from(path))),
+                _ => Err(format!(
+                    "Invalid cache backend `{value}`, expected `fs`, `memory` or `bundle:<path>`"
+                )),
+            },
+        }
+    }
+}
+
+pub fn open(spec: &BackendSpec) -> io::Result<Arc<dyn CacheBackend>> {
+    match spec {
+        BackendSpec::Fs => Ok(Arc::new(FsBackend)),
+        BackendSpec::Memory => Ok(MemoryBackend::global()),
+        BackendSpec::Bundle(path) => {
+            let mut bundles = BUNDLES.lock().unwrap();
+            if let Some(bundle) = bundles.get(path) {
+                return Ok(bundle.clone());
+            }
+            let bundle = Arc::new(BundleBackend::open(path)?);
+            bundles.insert(path.clone(), bundle.clone());
+            Ok(bundle)
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::ffi::{c_char, CStr, CString};
+
+use crate::directives::Frontend;
+use crate::error::{self, TransformError, TransformStage};
+use crate::pipeline::{CachePolicy, Pipeline, TransformOptions};
+
+/// Version of the C ABI, bumped on every incompatible change of the types or functions below.
+pub const LJP_ABI_VERSION: u32 = 1;
+
+pub const LJP_FLAG_OPT: u32 = 1 << 0;
+pub const LJP_FLAG_FORMAT: u32 = 1 << 1;
+pub const LJP_FLAG_PRETTY: u32 = 1 << 2;
+pub const LJP_FLAG_NO_COMMENT: u32 = 1 << 3;
+pub const LJP_FLAG_PRESERVE_LINES: u32 = 1 << 4;
+/// Never run the optimizer, even for files with the `opt` directive
+pub const LJP_FLAG_DISABLE_OPTIMIZER: u32 = 1 << 5;
+/// Read the defaults of the `ljp.toml` found above the file
+pub const LJP_FLAG_PROJECT_CONFIG: u32 = 1 << 6;
+/// Let env vars of the same name override the header params
+pub const LJP_FLAG_ENV_OVERRIDES: u32 = 1 << 7;
+
+pub const LJP_FRONTEND_LUA: u32 = 0;
+pub const LJP_FRONTEND_TEAL: u32 = 1;
+pub const LJP_FRONTEND_LUAU: u32 = 2;
+
+pub const LJP_CACHE_DISABLED: u32 = 0;
+pub const LJP_CACHE_READ_WRITE: u32 = 1;
+pub const LJP_CACHE_REFRESH: u32 = 2;
+
+/// Options of `ljp_transform_file()` and `ljp_transform_buffer()`.
+/// Passing NULL instead uses the same behavior as the LuaJIT loader(project config, `LJP_*` env vars, build cache).
+#[repr(C)]
+pub struct LjpOptions {
+    /// Must be `LJP_ABI_VERSION`
+    pub abi_version: u32,
+    /// `LJP_FLAG_*`, the directives of the file header are applied on top of them
+    pub flags: u32,
+    /// `LJP_FRONTEND_*`, the file header takes precedence
+    pub frontend: u32,
+    /// `LJP_CACHE_*`
+    pub cache_policy: u32,
+    /// NUL-terminated cache directory, NULL disables the cache
+    pub cache_dir: *const c_char,
+}
+
+struct ResultError {
+    message: CString,
+    file: CString,
+    line: usize,
+    column: usize,
+    stage: CString,
+    code: Option<CString>,
+    hint: Option<CString>,
+}
+
+/// The outcome of a transformation, owned by the caller until passed to `ljp_free_result()`.
+pub struct LjpResult {
+    /// NUL-terminated generated code or bytecode
+    code: Vec<u8>,
+    error: Option<ResultError>,
+}
+
+#[inline]
+fn to_cstring(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+impl LjpResult {
+    fn new(ret: Result<Vec<u8>, TransformError>) -> *mut LjpResult {
+        let result = match ret {
+            Ok(mut code) => {
+                code.push(0);
+                LjpResult { code, error: None }
+            }
+            Err(e) => LjpResult {
+                code: Vec::new(),
+                error: Some(ResultError {
+                    message: to_cstring(&e.to_string()),
+                    file: to_cstring(&e.file),
+                    line: e.line.unwrap_or(0),
+                    column: e.column.unwrap_or(0),
+                    stage: to_cstring(e.stage.as_str()),
+                    code: e.code.map(to_cstring),
+                    hint: e.hint.as_deref().map(to_cstring),
+                }),
+            },
+        };
+        Box::into_raw(Box::new(result))
+    }
+}
+
+fn invalid_argument(file: &str, message: &str) -> TransformError {
+    TransformError::new(TransformStage::Read, file, message)
+}
+
+fn options_to_pipeline(options: &LjpOptions) -> Result<Pipeline, String> {
+    if options.abi_version != LJP_ABI_VERSION {
+        return Err(format!(
+            "Unsupported ABI version {}, expected {}",
+            options.abi_version, LJP_ABI_VERSION
+        ));
+    }
+
+    let frontend = match options.frontend {
+        LJP_FRONTEND_LUA => Frontend::Lua,
+        LJP_FRONTEND_TEAL => Frontend::Teal,
+        LJP_FRONTEND_LUAU => Frontend::Luau,
+        frontend => return Err(format!("Invalid frontend {frontend}")),
+    };
+    let cache_policy = match options.cache_policy {
+        LJP_CACHE_DISABLED => CachePolicy::Disabled,
+        LJP_CACHE_READ_WRITE => CachePolicy::ReadWrite,
+        LJP_CACHE_REFRESH => CachePolicy::Refresh,
+        policy => return Err(format!("Invalid cache policy {policy}")),
+    };
+
+    let flag = |f: u32| options.flags & f != 0;
+    let mut transform_options = TransformOptions::new()
+        .frontend(frontend)
+        .optimize(flag(LJP_FLAG_OPT))
+        .format(flag(LJP_FLAG_FORMAT))
+        .pretty(flag(LJP_FLAG_PRETTY))
+        .no_comment(flag(LJP_FLAG_NO_COMMENT))
+        .preserve_lines(flag(LJP_FLAG_PRESERVE_LINES))
+        .disable_optimizer(flag(LJP_FLAG_DISABLE_OPTIMIZER))
+        .project_config(flag(LJP_FLAG_PROJECT_CONFIG))
+        .env_overrides(flag(LJP_FLAG_ENV_OVERRIDES));
+    if !options.cache_dir.is_null() {
+        let cache_dir = unsafe { CStr::from_ptr(options.cache_dir) }.to_string_lossy();
+        transform_options = transform_options.cache(cache_policy, cache_dir.as_ref());
+    }
+    Ok(transform_options.build())
+}
+
+fn transform_with_options(
+    options: *const LjpOptions,
+    path: &str,
+    source: impl FnOnce() -> Result<String, TransformError>,
+    default: impl FnOnce() -> Result<Vec<u8>, TransformError>,
+) -> Result<Vec<u8>, TransformError> {
+    if options.is_null() {
+        return default();
+    }
+
+    let pipeline = options_to_pipeline(unsafe { &*options })
+        .map_err(|message| invalid_argument(path, &message))?;
+    pipeline
+        .transform(&source()?, path)
+        .map(|output| output.code.into_bytes())
+}
+
+/// Version of the C ABI implemented by this library, compare it with `LJP_ABI_VERSION`.
+#[no_mangle]
+pub extern "C" fn ljp_version() -> u32 {
+    LJP_ABI_VERSION
+}
+
+/// Version of the luajit_pro_helper crate, e.g. `0.1.0`.
+#[no_mangle]
+pub extern "C" fn ljp_version_string() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Transform the file at `path`. `options` may be NULL, see `ljp_options`.
+/// With NULL `options` the result is LuaJIT bytecode if enabled by the project config or `LJP_BYTECODE`.
+/// Never returns NULL, the result must be released by `ljp_free_result()`.
+#[no_mangle]
+pub extern "C" fn ljp_transform_file(
+    path: *const c_char,
+    options: *const LjpOptions,
+) -> *mut LjpResult {
+    if path.is_null() {
+        return LjpResult::new(Err(invalid_argument("?", "`path` is NULL")));
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+
+    LjpResult::new(transform_with_options(
+        options,
+        &path,
+        || {
+            std::fs::read_to_string(path.as_ref()).map_err(|e| {
+                TransformError::new(
+                    TransformStage::Read,
+                    &path,
+                    format!("Failed to read file => {}", e),
+                )
+            })
+        },
+        || error::catch(&path, || crate::load_lua_file(&path, true)),
+    ))
+}
+
+/// Loader internal, declared by patch/src/lj_load.c: `ljp_transform_file(path, NULL)` which returns the
+/// generated code instead of the bytecode unless `allow_bytecode`, for `loadfile(path, "t")`.
+#[no_mangle]
+pub extern "C" fn ljp_load_file(path: *const c_char, allow_bytecode: bool) -> *mut LjpResult {
+    if path.is_null() {
+        return LjpResult::new(Err(invalid_argument("?", "`path` is NULL")));
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+    LjpResult::new(error::catch(&path, || {
+        crate::load_lua_file(&path, allow_bytecode)
+    }))
+}
+
+/// Transform the `size` bytes of `buf`, `chunk_name` follows the LuaJIT convention(`@file`, `=name` or the code itself).
+/// With NULL `options` a chunk without the luajit-pro header is returned unchanged.
+/// Never returns NULL, the result must be released by `ljp_free_result()`.
+#[no_mangle]
+pub extern "C" fn ljp_transform_buffer(
+    chunk_name: *const c_char,
+    buf: *const c_char,
+    size: usize,
+    options: *const LjpOptions,
+) -> *mut LjpResult {
+    let chunk_name = if chunk_name.is_null() {
+        "?".into()
+    } else {
+        unsafe { CStr::from_ptr(chunk_name) }.to_string_lossy()
+    };
+    if buf.is_null() && size != 0 {
+        return LjpResult::new(Err(invalid_argument(&chunk_name, "`buf` is NULL")));
+    }
+    let code = if size == 0 {
+        String::new()
+    } else {
+        let code = unsafe { std::slice::from_raw_parts(buf as *const u8, size) };
+        String::from_utf8_lossy(code).into_owned()
+    };
+    let path = crate::chunk_name_to_path(&chunk_name);
+
+    LjpResult::new(transform_with_options(
+        options,
+        &path,
+        || Ok(code.clone()),
+        || {
+            error::catch(&path, || {
+                crate::transform_lua_string(&chunk_name, &code).into_bytes()
+            })
+        },
+    ))
+}
+
+/// Whether the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_ok(result: *const LjpResult) -> bool {
+    !result.is_null() && unsafe { &*result }.error.is_none()
+}
+
+/// The generated code or bytecode(NUL-terminated), its length is written into `size` if it is not NULL.
+/// Returns NULL if the transformation failed. Valid until the result is released.
+#[no_mangle]
+pub extern "C" fn ljp_result_code(result: *const LjpResult, size: *mut usize) -> *const c_char {
+    if !ljp_result_ok(result) {
+        return std::ptr::null();
+    }
+    let result = unsafe { &*result };
+    if !size.is_null() {
+        unsafe { *size = result.code.len() - 1 };
+    }
+    result.code.as_ptr() as *const c_char
+}
+
+/// The full error message, e.g. `foo.lua:12: [luajit-pro comp_time] ...`.
+/// Returns NULL if the transformation succeeded. Valid until the result is released.
+#[no_mangle]
+pub extern "C" fn ljp_result_error(result: *const LjpResult) -> *const c_char {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()) {
+        Some(error) => error.message.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// The file the error was raised in, NULL if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_file(result: *const LjpResult) -> *const c_char {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()) {
+        Some(error) => error.file.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// The 1-based line of the error, 0 if unknown or if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_line(result: *const LjpResult) -> usize {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()) {
+        Some(error) => error.line,
+        None => 0,
+    }
+}
+
+/// The 1-based column of the error, 0 if unknown or if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_column(result: *const LjpResult) -> usize {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()) {
+        Some(error) => error.column,
+        None => 0,
+    }
+}
+
+/// The error code(e.g. `LJP0201`), NULL if the error has none or if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_code(result: *const LjpResult) -> *const c_char {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()?.code.as_ref()) {
+        Some(code) => code.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// A hint on how to fix the error, NULL if the error has none or if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_hint(result: *const LjpResult) -> *const c_char {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()?.hint.as_ref()) {
+        Some(hint) => hint.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// The stage the error was raised in(e.g. `parse`, `comp_time`), NULL if the transformation succeeded.
+#[no_mangle]
+pub extern "C" fn ljp_result_error_stage(result: *const LjpResult) -> *const c_char {
+    match unsafe { result.as_ref() }.and_then(|r| r.error.as_ref()) {
+        Some(error) => error.stage.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Release a result returned by `ljp_transform_file()` or `ljp_transform_buffer()`, NULL is ignored.
+#[no_mangle]
+pub extern "C" fn ljp_free_result(result: *mut LjpResult) {
+    if !result.is_null() {
+        drop(unsafe { Box::from_raw(result) });
+    }
+}
+
+/// Number of loads served by the build cache in this process.
+#[no_mangle]
+pub extern "C" fn ljp_cache_hits() -> u64 {
+    crate::cache::stats().hits
+}
+
+/// Number of loads in this process that had to transform the source(missing, stale or corrupted cache entry).
+#[no_mangle]
+pub extern "C" fn ljp_cache_misses() -> u64 {
+    crate::cache::stats().misses
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use globset::{GlobBuilder, GlobMatcher};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::cache_backend::{BackendKind, BackendSpec};
+use crate::deps;
+use crate::directives::{Directives, ParamValue};
+use crate::error::{self, TransformStage};
+use crate::lua_optimizer::OPTIMIZER_PASSES;
+
+pub const CONFIG_FILE_NAME: &str = "ljp.toml";
+
+/// Entries marking the root of a repository, `.git` is a file in worktrees and submodules
+const VCS_MARKERS: &[&str] = &[".git", ".hg", ".svn", ".jj", ".fslckout", "_darcs"];
+
+lazy_static! {
+    // Parsed config files and the mtime they were parsed at
+    static ref CONFIGS: Mutex<HashMap<PathBuf, (Option<SystemTime>, Arc<ProjectConfig>)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The project config(`ljp.toml`), found by walking up from the source file.
+///
+/// ```toml
+/// # Relative to the directory of ljp.toml, `LJP_OUT_DIR` takes precedence.
+/// # Defaults to `.luajit_pro/build_cache` next to ljp.toml
+/// cache_dir = ".luajit_pro/build_cache"
+/// # "fs"(default), "memory" or "bundle", `LJP_CACHE_BACKEND`(`fs`, `memory`, `bundle:<path>`) takes precedence
+/// cache_backend = "bundle"
+/// # The read-only archive built by `ljp cache bundle`, relative to the directory of ljp.toml
+/// cache_bundle = "dist/cache.ljpb"
+///
+/// # Default params of every file
+/// [params]
+/// LOG_LEVEL = 2
+///
+/// # Default directives and params of the matching files(relative to the directory of ljp.toml),
+/// # every matching entry is applied in order
+/// [[files]]
+/// glob = "src/**/*.lua"
+/// directives = ["opt", "format"]
+/// params = { TARGET = "openresty" }
+///
+/// [format]
+/// column_width = 120
+/// indent_type = "spaces"
+/// indent_width = 4
+/// quote_style = "auto-prefer-double"
+///
+/// [optimizer]
+/// passes = ["comp_time_enum", "used"]
+///
+/// # Hand LuaJIT bytecode to the loader, `LJP_BYTECODE`/`LJP_BYTECODE_STRIP` take precedence
+/// [bytecode]
+/// enabled = true
+/// strip = false
+/// ```
+///
+/// Precedence: config < file header < env vars.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Path of the config file itself
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub cache_dir: Option<String>,
+    pub cache_backend: Option<BackendKind>,
+    pub cache_bundle: Option<String>,
+    pub params: BTreeMap<String, toml::Value>,
+    pub files: Vec<FileRule>,
+    pub format: FormatConfig,
+    pub optimizer: OptimizerConfig,
+    pub bytecode: BytecodeConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRule {
+    pub glob: String,
+    #[serde(default)]
+    pub directives: Vec<String>,
+    #[serde(default)]
+    pub params: BTreeMap<String, toml::Value>,
+    #[serde(skip)]
+    matcher: Option<GlobMatcher>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatConfig {
+    pub column_width: Option<usize>,
+    pub indent_width: Option<usize>,
+    pub indent_type: Option<IndentType>,
+    pub quote_style: Option<QuoteStyle>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndentType {
+    Tabs,
+    Spaces,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuoteStyle {
+    AutoPreferDouble,
+    AutoPreferSingle,
+    ForceDouble,
+    ForceSingle,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptimizerConfig {
+    /// Enabled passes(see `OPTIMIZER_PASSES`), all of them if unset
+    pub passes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BytecodeConfig {
+    /// Cache the generated code as bytecode too and load that instead of the source
+    pub enabled: bool,
+    /// Drop the debug info(line numbers, local names), runtime errors no longer point at a line
+    pub strip: bool,
+}
+
+impl FormatConfig {
+    /// Override the options which are set in `other`.
+    pub fn merge(&mut self, other: &FormatConfig) {
+        self.column_width = other.column_width.or(self.column_width);
+        self.indent_width = other.indent_width.or(self.indent_width);
+        self.indent_type = other.indent_type.or(self.indent_type);
+        self.quote_style = other.quote_style.or(self.quote_style);
+    }
+
+    pub fn to_stylua(&self) -> stylua_lib::Config {
+        let mut cfg = stylua_lib::Config::new();
+        cfg.column_width = self.column_width.unwrap_or(240);
+        if let Some(indent_width) = self.indent_width {
+            cfg.indent_width = indent_width;
+        }
+        if let Some(indent_type) = self.indent_type {
+            cfg.indent_type = match indent_type {
+                IndentType::Tabs => stylua_lib::IndentType::Tabs,
+                IndentType::Spaces => stylua_lib::IndentType::Spaces,
+            };
+        }
+        if let Some(quote_style) = self.quote_style {
+            cfg.quote_style = match quote_style {
+                QuoteStyle::AutoPreferDouble => stylua_lib::QuoteStyle::AutoPreferDouble,
+                QuoteStyle::AutoPreferSingle => stylua_lib::QuoteStyle::AutoPreferSingle,
+                QuoteStyle::ForceDouble => stylua_lib::QuoteStyle::ForceDouble,
+                QuoteStyle::ForceSingle => stylua_lib::QuoteStyle::ForceSingle,
+            };
+        }
+        cfg
+    }
+}
+
+fn to_param_value(key: &str, value: &toml::Value) -> Result<ParamValue, String> {
+    match value {
+        toml::Value::Boolean(b) => Ok(ParamValue::Bool(*b)),
+        toml::Value::Integer(int) => Ok(ParamValue::Int(*int)),
+        toml::Value::Float(float) if float.is_finite() => Ok(ParamValue::Float(*float)),
+        toml::Value::String(s) => Ok(ParamValue::Str(s.clone())),
+        _ => Err(format!(
+            "Invalid value of param `{key}`, expected a boolean, a number or a string"
+        )),
+    }
+}
+
+fn merge_params(
+    params: &mut Vec<(String, ParamValue)>,
+    table: &BTreeMap<String, toml::Value>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let value = to_param_value(key, value)?;
+        match params.iter_mut().find(|(k, _)| k == key) {
+            Some(param) => param.1 = value,
+            None => params.push((key.clone(), value)),
+        }
+    }
+    Ok(())
+}
+
+impl ProjectConfig {
+    pub fn load(path: &Path) -> Result<ProjectConfig, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {} => {}", path.display(), e))?;
+        let mut config: ProjectConfig =
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        config.path = path.to_path_buf();
+
+        for rule in &mut config.files {
+            let glob = GlobBuilder::new(&rule.glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid glob `{}`: {}", rule.glob, e))?;
+            rule.matcher = Some(glob.compile_matcher());
+        }
+        if let Some(passes) = &config.optimizer.passes {
+            if let Some(pass) = passes
+                .iter()
+                .find(|p| !OPTIMIZER_PASSES.contains(&p.as_str()))
+            {
+                return Err(format!(
+                    "Unknown optimizer pass `{pass}`, expected one of: {}",
+                    OPTIMIZER_PASSES.join(", ")
+                ));
+            }
+        }
+        if config.cache_backend == Some(BackendKind::Bundle) && config.cache_bundle.is_none() {
+            return Err("`cache_backend = \"bundle\"` requires `cache_bundle`".to_string());
+        }
+        // Report invalid directives and params now instead of on the first matching file
+        for rule in &config.files {
+            let mut directives = Directives::default();
+            for name in &rule.directives {
+                directives.apply_item(name)?;
+            }
+            directives.check()?;
+            merge_params(&mut Vec::new(), &rule.params)?;
+        }
+        merge_params(&mut Vec::new(), &config.params)?;
+
+        Ok(config)
+    }
+
+    /// Directory of the config file, globs and `cache_dir` are relative to it.
+    pub fn root(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    pub fn cache_dir(&self) -> Option<String> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        Some(self.root().join(cache_dir).to_string_lossy().into_owned())
+    }
+
+    /// The backend set by `cache_backend`, `None` if unset.
+    pub fn cache_backend(&self) -> Option<BackendSpec> {
+        Some(match self.cache_backend? {
+            BackendKind::Fs => BackendSpec::Fs,
+            BackendKind::Memory => BackendSpec::Memory,
+            // Checked by `load()`
+            BackendKind::Bundle => {
+                BackendSpec::Bundle(self.root().join(self.cache_bundle.as_ref()?))
+            }
+        })
+    }
+
+    fn matching_rules<'a>(&'a self, file_path: &str) -> impl Iterator<Item = &'a FileRule> {
+        let relative = std::fs::canonicalize(file_path)
+            .ok()
+            .and_then(|p| p.strip_prefix(self.root()).ok().map(|p| p.to_path_buf()));
+        self.files
+            .iter()
+            .filter(move |rule| match (&relative, &rule.matcher) {
+                (Some(relative), Some(matcher)) => matcher.is_match(relative),
+                _ => false,
+            })
+    }
+
+    /// Whether any `[[files]]` entry matches `file_path`.
+    pub fn matches(&self, file_path: &str) -> bool {
+        self.matching_rules(file_path).next().is_some()
+    }
+
+    /// Default directives of `file_path`: the `[params]` table, then every matching `[[files]]` entry in order.
+    pub fn directives_for(&self, file_path: &str) -> Result<Directives, String> {
+        let mut directives = Directives::default();
+        let mut params = Vec::new();
+        merge_params(&mut params, &self.params)?;
+        for rule in self.matching_rules(file_path) {
+            for name in &rule.directives {
+                if !directives.names().contains(&name.as_str()) {
+                    directives.apply_item(name)?;
+                }
+            }
+            merge_params(&mut params, &rule.params)?;
+        }
+        if !params.is_empty() {
+            directives.params = Some(params);
+        }
+        Ok(directives)
+    }
+}
+
+/// The first of the directory of `file_path`(or `file_path` itself if it is a directory) and its parents
+/// for which `f` returns `Some`.
+fn find_upwards<T>(file_path: &str, f: impl Fn(&Path) -> Option<T>) -> Option<T> {
+    let path = std::fs::canonicalize(file_path).ok()?;
+    let mut dir = if path.is_dir() {
+        Some(path.as_path())
+    } else {
+        path.parent()
+    };
+    while let Some(d) = dir {
+        if let Some(found) = f(d) {
+            return Some(found);
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+/// Find `ljp.toml` in the directory of `file_path` or any of its parents.
+pub fn find_config_file(file_path: &str) -> Option<PathBuf> {
+    find_upwards(file_path, |dir| {
+        let config_path = dir.join(CONFIG_FILE_NAME);
+        config_path.is_file().then_some(config_path)
+    })
+}
+
+/// Root of the repository(git, mercurial, subversion, ...) `file_path` belongs to.
+pub fn find_vcs_root(file_path: &str) -> Option<PathBuf> {
+    find_upwards(file_path, |dir| {
+        VCS_MARKERS
+            .iter()
+            .any(|marker| dir.join(marker).exists())
+            .then(|| dir.to_path_buf())
+    })
+}
+
+/// The project config of `file_path`, `None` if there is no `ljp.toml` above it.
+/// Configs are parsed once and reloaded when the file changes.
+pub fn project_config(file_path: &str) -> Result<Option<Arc<ProjectConfig>>, String> {
+    match find_config_file(file_path) {
+        Some(config_path) => load_config(&config_path).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The config at `config_path`, parsed once and reloaded when the file changes.
+pub fn load_config(config_path: &Path) -> Result<Arc<ProjectConfig>, String> {
+    let mtime = std::fs::metadata(config_path)
+        .and_then(|m| m.modified())
+        .ok();
+
+    let mut configs = CONFIGS.lock().unwrap();
+    if let Some((cached_mtime, config)) = configs.get(config_path) {
+        if *cached_mtime == mtime {
+            return Ok(config.clone());
+        }
+    }
+
+    let config = Arc::new(ProjectConfig::load(config_path)?);
+    configs.insert(config_path.to_path_buf(), (mtime, config.clone()));
+    Ok(config)
+}
+
+/// Same as `project_config()` but raises a config error, the config file is recorded as a dependency.
+pub fn load_for(file_path: &str) -> Option<Arc<ProjectConfig>> {
+    let config = project_config(file_path)
+        .unwrap_or_else(|e| error::raise(TransformStage::Config, None, e))?;
+    deps::record(&config.path.to_string_lossy());
+    Some(config)
+}
@@ -0,0 +1,74 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cache;
+
+/// Inputs of a transformation besides the source file itself.
+#[derive(Debug, Clone, Default)]
+pub struct Tracked {
+    /// Files read while transforming(includes, Teal modules, files opened at comp-time)
+    pub files: BTreeSet<String>,
+    /// Env vars read at comp-time with the value they had, `None` if unset
+    pub env: BTreeMap<String, Option<String>>,
+}
+
+impl Tracked {
+    fn merge(&mut self, other: Tracked) {
+        self.files.extend(other.files);
+        // The first read wins, that is the value the generated code was based on
+        for (key, value) in other.env {
+            self.env.entry(key).or_insert(value);
+        }
+    }
+}
+
+thread_local! {
+    static TRACKED: RefCell<Option<Tracked>> = RefCell::new(None);
+}
+
+/// Record a file read while transforming. Nothing is recorded outside of `track()`.
+pub fn record(file_path: &str) {
+    TRACKED.with(|tracked| {
+        if let Some(tracked) = tracked.borrow_mut().as_mut() {
+            tracked.files.insert(cache::canonical_path(file_path));
+        }
+    });
+}
+
+/// Record an env var read at comp-time. Nothing is recorded outside of `track()`.
+pub fn record_env(key: &str, value: Option<String>) {
+    TRACKED.with(|tracked| {
+        if let Some(tracked) = tracked.borrow_mut().as_mut() {
+            tracked.env.entry(key.to_string()).or_insert(value);
+        }
+    });
+}
+
+struct TrackGuard {
+    prev: Option<Tracked>,
+}
+
+impl Drop for TrackGuard {
+    fn drop(&mut self) {
+        TRACKED.with(|tracked| {
+            let mut tracked = tracked.borrow_mut();
+            let current = tracked.take();
+            let mut prev = self.prev.take();
+            // Nested tracking also reports its inputs to the outer one
+            if let (Some(prev), Some(current)) = (prev.as_mut(), current) {
+                prev.merge(current);
+            }
+            *tracked = prev;
+        });
+    }
+}
+
+/// Run `f` and return every input recorded while it was running.
+pub fn track<T>(f: impl FnOnce() -> T) -> (T, Tracked) {
+    let _guard = TrackGuard {
+        prev: TRACKED.with(|tracked| tracked.borrow_mut().replace(Tracked::default())),
+    };
+    let ret = f();
+    let tracked = TRACKED.with(|tracked| tracked.borrow().clone().unwrap_or_default());
+    (ret, tracked)
+}
@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::ProjectConfig;
+use crate::error::{self, TransformStage};
+
+const HEADER_PREFIX: &str = "--[[luajit-pro";
+const BOM: &str = "\u{feff}";
+
+/// Directive names recognized in the `--[[luajit-pro, ...]]` header.
+pub const DIRECTIVES: &[&str] = &[
+    "teal",
+    "luau",
+    "syntax-only",
+    "opt",
+    "format",
+    "pretty",
+    "no-comment",
+    "no-cache",
+    "preserve-lines",
+];
+
+/// The language the source is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Frontend {
+    #[default]
+    Lua,
+    Teal,
+    Luau,
+}
+
+/// A value of the header param table.
+///
+/// `0` and `1` are booleans(as they have always been), other integers are numbers, write `1.0` for the number.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl ParamValue {
+    /// Parse a value written in the header, e.g. `true`, `2`, `0.5` or `"openresty"`.
+    pub fn parse(value: &str) -> Result<ParamValue, String> {
+        let value = value.trim();
+        match value {
+            "true" | "1" => return Ok(ParamValue::Bool(true)),
+            "false" | "0" => return Ok(ParamValue::Bool(false)),
+            _ => {}
+        }
+
+        if let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            return parse_string(value, quote).map(ParamValue::Str);
+        }
+        if let Ok(int) = value.parse::<i64>() {
+            return Ok(ParamValue::Int(int));
+        }
+        match value.parse::<f64>() {
+            Ok(float) if float.is_finite() && !value.starts_with(['+', '.']) => {
+                Ok(ParamValue::Float(float))
+            }
+            _ => Err(format!(
+                "Invalid value `{value}`, expected `true`, `false`, a number or a quoted string"
+            )),
+        }
+    }
+
+    /// Parse a value overriding `default` from an env var. Strings may be given without quotes.
+    pub fn parse_override(value: &str, default: &ParamValue) -> Result<ParamValue, String> {
+        let parsed = match ParamValue::parse(value) {
+            Err(_) if matches!(default, ParamValue::Str(_)) => {
+                return Ok(ParamValue::Str(value.to_string()))
+            }
+            parsed => parsed?,
+        };
+
+        match (default, parsed) {
+            (ParamValue::Float(_), ParamValue::Int(int)) => Ok(ParamValue::Float(int as f64)),
+            (ParamValue::Int(_) | ParamValue::Float(_), ParamValue::Bool(b)) => {
+                // `0` and `1` are parsed as booleans
+                Ok(ParamValue::Int(b as i64))
+            }
+            (ParamValue::Str(_), parsed @ ParamValue::Str(_)) => Ok(parsed),
+            (ParamValue::Str(_), _) => Ok(ParamValue::Str(value.to_string())),
+            (default, parsed) if default.type_name() == parsed.type_name() => Ok(parsed),
+            (default, parsed) => Err(format!(
+                "Expected {}, found {} `{value}`",
+                default.type_name(),
+                parsed.type_name()
+            )),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ParamValue::Bool(_) => "a boolean",
+            ParamValue::Int(_) | ParamValue::Float(_) => "a number",
+            ParamValue::Str(_) => "a string",
+        }
+    }
+
+    /// The value as a Lua literal.
+    pub fn to_lua(&self) -> String {
+        match self {
+            ParamValue::Bool(b) => b.to_string(),
+            ParamValue::Int(int) => int.to_string(),
+            // `{:?}` keeps the fraction(`2.0`), so the literal is read back as a float
+            ParamValue::Float(float) => format!("{float:?}"),
+            ParamValue::Str(s) => {
+                let mut literal = String::from("\"");
+                for c in s.chars() {
+                    match c {
+                        '"' => literal.push_str("\\\""),
+                        '\\' => literal.push_str("\\\\"),
+                        '\n' => literal.push_str("\\n"),
+                        '\r' => literal.push_str("\\r"),
+                        '\t' => literal.push_str("\\t"),
+                        c => literal.push(c),
+                    }
+                }
+                literal.push('"');
+                literal
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_lua())
+    }
+}
+
+fn parse_string(value: &str, quote: char) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = value[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('t') => result.push('\t'),
+                Some(c @ ('\\' | '"' | '\'')) => result.push(c),
+                Some(c) => return Err(format!("Invalid escape `\\{c}` in `{value}`")),
+                None => break,
+            },
+            c if c == quote => {
+                if !chars.as_str().is_empty() {
+                    return Err(format!(
+                        "Unexpected `{}` after the string `{value}`",
+                        chars.as_str()
+                    ));
+                }
+                return Ok(result);
+            }
+            c => result.push(c),
+        }
+    }
+    Err(format!("Unterminated string `{value}`"))
+}
+
+/// The parsed `--[[luajit-pro, <directive>, ..., {key = value, ...}]]` header.
+///
+/// The flags are `None` if the header(or config, or options) does not mention them, so that `merge()` only
+/// overrides the ones a layer sets: `format = false` in a header turns off a `format` enabled in ljp.toml.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directives {
+    pub frontend: Frontend,
+    /// Only check the Teal syntax, skip type checking
+    pub syntax_only: Option<bool>,
+    pub opt: Option<bool>,
+    pub format: Option<bool>,
+    /// `no-comment` + `format`
+    pub pretty: Option<bool>,
+    pub no_comment: Option<bool>,
+    pub no_cache: Option<bool>,
+    pub preserve_lines: Option<bool>,
+    /// Params of the `{key = value, ...}` table in declaration order, `None` if the header has no table
+    pub params: Option<Vec<(String, ParamValue)>>,
+}
+
+/// Whether `line` starts with the luajit-pro header.
+pub fn has_header(line: &str) -> bool {
+    line.trim_start().starts_with(HEADER_PREFIX)
+}
+
+/// Byte offset of the line which holds the header, i.e. after the UTF-8 BOM and the shebang line
+/// (any first line starting with `#`, which LuaJIT skips as well).
+pub fn header_start(code: &str) -> usize {
+    let start = if code.starts_with(BOM) { BOM.len() } else { 0 };
+    if !code[start..].starts_with('#') {
+        return start;
+    }
+    match code[start..].find('\n') {
+        Some(pos) => start + pos + 1,
+        None => code.len(),
+    }
+}
+
+/// Whether `code` has the luajit-pro header, on its first line or after a shebang line.
+pub fn starts_with_header(code: &str) -> bool {
+    has_header(code[header_start(code)..].lines().next().unwrap_or(""))
+}
+
+/// 1-based line of the header in `code`.
+pub fn header_line(code: &str) -> usize {
+    code[..header_start(code)].matches('\n').count() + 1
+}
+
+/// Byte offset of the end of the line with the closing `]]` of the header(line break included),
+/// `None` if `code` has no header or the header is not terminated.
+pub fn header_end(code: &str) -> Option<usize> {
+    if !starts_with_header(code) {
+        return None;
+    }
+    let start = header_start(code) + code[header_start(code)..].find(HEADER_PREFIX).unwrap();
+    let end = start + code[start..].find("]]")? + 2;
+    Some(match code[end..].find('\n') {
+        Some(pos) => end + pos + 1,
+        None => code.len(),
+    })
+}
+
+/// Remove the header comment of `code`, its lines are kept blank and the code after the closing `]]`
+/// stays on its line so that the line numbers do not change.
+pub fn strip_header(code: &str) -> String {
+    if !starts_with_header(code) {
+        return code.to_string();
+    }
+    let header_start = header_start(code);
+    let line_end = code[header_start..]
+        .find('\n')
+        .map_or(code.len(), |pos| header_start + pos);
+    let start = header_start + code[header_start..].find(HEADER_PREFIX).unwrap();
+    match code[start..].find("]]") {
+        Some(end) => {
+            let header = &code[start..start + end];
+            let rest = code[start + end + 2..].trim_start_matches([' ', '\t']);
+            format!(
+                "{}{}{}",
+                &code[..start],
+                "\n".repeat(header.matches('\n').count()),
+                rest
+            )
+        }
+        None => format!("{}{}", &code[..start], &code[line_end..]),
+    }
+}
+
+/// Fold the header `header`(from `header_start()` to `header_end()`) into a single line without comments,
+/// e.g. `--[[luajit-pro, teal, opt, {N = 1}]]`.
+pub(crate) fn fold_header(header: &str) -> String {
+    let start = header.find(HEADER_PREFIX).unwrap() + HEADER_PREFIX.len();
+    let end = start + header[start..].find("]]").unwrap();
+
+    let mut body = String::new();
+    for item in strip_comments(&header[start..end]).lines() {
+        let item = item.trim().trim_end_matches(',').trim();
+        if item.is_empty() {
+            continue;
+        }
+        if !(item.starts_with(',') || body.ends_with('{') || item.starts_with('}')) {
+            body.push_str(", ");
+        }
+        body.push_str(item);
+    }
+    format!(
+        "{}{}]] {}",
+        &header[..start],
+        body,
+        header[end + 2..].trim()
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Split off the shebang line of `code`(see `header_start()`) for the parsers, which do not know it.
+///
+/// Returns the shebang and `code` without the BOM and with the shebang turned into a comment, so that
+/// the line numbers do not change and `restore_shebang()` can find it in the generated code.
+pub(crate) fn hide_shebang(code: &str) -> (Option<&str>, String) {
+    let code = code.strip_prefix(BOM).unwrap_or(code);
+    if !code.starts_with('#') {
+        return (None, code.to_string());
+    }
+    let line_end = code.find('\n').unwrap_or(code.len());
+    let shebang = code[..line_end].trim_end_matches('\r');
+    (Some(shebang), format!("--{}", code))
+}
+
+/// Put `shebang` back on the first line of the code generated from the output of `hide_shebang()`.
+pub(crate) fn restore_shebang(shebang: &str, code: &str) -> String {
+    match code.strip_prefix(&format!("--{shebang}")) {
+        Some(rest) => format!("{shebang}{rest}"),
+        // The comment was removed(`no-comment`, `pretty`)
+        None => format!("{shebang}\n{code}"),
+    }
+}
+
+/// Remove the `-- ...` comments from the lines of the header body.
+fn strip_comments(body: &str) -> String {
+    let mut lines = Vec::new();
+    for line in body.split('\n') {
+        let mut quote = None;
+        let mut escaped = false;
+        let mut end = line.len();
+        for (i, c) in line.char_indices() {
+            if let Some(q) = quote {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            match c {
+                '"' | '\'' => quote = Some(c),
+                '-' if line[i..].starts_with("--") => {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        lines.push(&line[..end]);
+    }
+    lines.join("\n")
+}
+
+/// Split `s` on the commas which are not inside of a `{...}` or a quoted string.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                items.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&s[start..]);
+    items
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_params(table: &str) -> Result<Vec<(String, ParamValue)>, String> {
+    let mut params: Vec<(String, ParamValue)> = Vec::new();
+    for kv in split_top_level(table) {
+        let kv = kv.trim();
+        if kv.is_empty() {
+            continue;
+        }
+
+        let Some((key, value)) = kv.split_once('=') else {
+            return Err(format!("Invalid param `{kv}`, expected `key = value`"));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if !is_identifier(key) {
+            return Err(format!("Invalid param name `{key}`"));
+        }
+        if value.is_empty() {
+            return Err(format!("Missing value of param `{key}`"));
+        }
+        if params.iter().any(|(k, _)| k == key) {
+            return Err(format!("Duplicate param `{key}`"));
+        }
+        let value = ParamValue::parse(value).map_err(|e| format!("Param `{key}`: {e}"))?;
+        params.push((key.to_string(), value));
+    }
+    Ok(params)
+}
+
+impl Directives {
+    /// Parse the header at the start of `code`, returns `Ok(None)` if `code` has no luajit-pro header.
+    ///
+    /// The header is either a single line, `--[[luajit-pro, teal, {N = 1}]]`, or a block with the items
+    /// on their own lines(the commas are optional) and `--` comments:
+    ///
+    /// ```lua
+    /// --[[luajit-pro
+    ///     teal
+    ///     opt -- inline the comp-time enums
+    ///     {
+    ///         N = 1,
+    ///         TARGET = "openresty",
+    ///     }
+    /// ]]
+    /// ```
+    ///
+    /// `format = false` disables a directive enabled by the project config.
+    /// The header ends at the first `]]`, even one in a comment. It may follow a UTF-8 BOM and a shebang line.
+    pub fn parse(code: &str) -> Result<Option<Directives>, String> {
+        if !starts_with_header(code) {
+            return Ok(None);
+        }
+        let code = &code[header_start(code)..];
+        let rest = &code[code.find(HEADER_PREFIX).unwrap() + HEADER_PREFIX.len()..];
+        let Some(end) = rest.find("]]") else {
+            return Err("Unterminated header, expected `]]`".to_string());
+        };
+        let body = strip_comments(&rest[..end]);
+        let body = body.trim_start_matches([' ', '\t', '\r']);
+        let body = if body.trim().is_empty() {
+            ""
+        } else if let Some(body) = body.strip_prefix(',') {
+            body
+        } else if body.starts_with('\n') {
+            body
+        } else {
+            return Err(format!(
+                "Expected `,` or a line break after `luajit-pro`, found `{}`",
+                body.lines().next().unwrap_or("").trim()
+            ));
+        };
+        // Line breaks separate the items like commas, blank items are skipped
+        let body = body.replace('\n', ",");
+
+        let mut directives = Directives::default();
+        let mut seen: Vec<&str> = Vec::new();
+        for item in split_top_level(&body) {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+
+            if let Some(table) = item.strip_prefix('{') {
+                let Some(table) = table.strip_suffix('}') else {
+                    return Err(format!("Unterminated param table `{item}`"));
+                };
+                if directives.params.is_some() {
+                    return Err("Duplicate param table".to_string());
+                }
+                directives.params = Some(parse_params(table)?);
+                continue;
+            }
+
+            let name = item.split_once('=').map_or(item, |(name, _)| name).trim();
+            if seen.contains(&name) {
+                return Err(format!("Duplicate directive `{name}`"));
+            }
+            seen.push(name);
+            directives.apply_item(item)?;
+        }
+
+        directives.check()?;
+        Ok(Some(directives))
+    }
+
+    /// Enable the directive `name`.
+    pub fn apply(&mut self, name: &str) -> Result<(), String> {
+        self.set(name, true)
+    }
+
+    /// Apply a directive item of the header or ljp.toml: `name` enables it, `name = false` disables it.
+    pub fn apply_item(&mut self, item: &str) -> Result<(), String> {
+        let Some((name, value)) = item.split_once('=') else {
+            return self.apply(item.trim());
+        };
+        let (name, value) = (name.trim(), value.trim());
+        match value {
+            "true" => self.set(name, true),
+            "false" => self.set(name, false),
+            _ => Err(format!(
+                "Invalid value `{value}` of directive `{name}`, expected `true` or `false`"
+            )),
+        }
+    }
+
+    /// Enable or disable the directive `name`.
+    pub fn set(&mut self, name: &str, enable: bool) -> Result<(), String> {
+        let flag = match name {
+            "teal" | "luau" if !enable => {
+                return Err(format!("`{name}` cannot be disabled"));
+            }
+            "teal" => {
+                if self.frontend == Frontend::Luau {
+                    return Err("Cannot use both `luau` and `teal`".to_string());
+                }
+                self.frontend = Frontend::Teal;
+                return Ok(());
+            }
+            "luau" => {
+                if self.frontend == Frontend::Teal {
+                    return Err("Cannot use both `luau` and `teal`".to_string());
+                }
+                self.frontend = Frontend::Luau;
+                return Ok(());
+            }
+            "syntax-only" => &mut self.syntax_only,
+            "opt" => &mut self.opt,
+            "format" => &mut self.format,
+            "pretty" => &mut self.pretty,
+            "no-comment" => &mut self.no_comment,
+            "no-cache" => &mut self.no_cache,
+            "preserve-lines" => &mut self.preserve_lines,
+            _ => {
+                return Err(format!(
+                    "Unknown directive `{name}`, expected one of: {}",
+                    DIRECTIVES.join(", ")
+                ))
+            }
+        };
+        *flag = Some(enable);
+        Ok(())
+    }
+
+    /// Reject the combinations of directives that make no sense.
+    pub fn check(&self) -> Result<(), String> {
+        if self.syntax_only == Some(true) && self.frontend != Frontend::Teal {
+            return Err("`syntax-only` can only be used with `teal`".to_string());
+        }
+        Ok(())
+    }
+
+    /// Override the directives with `other`: its frontend wins if set, so do the flags it enables or
+    /// disables, and its params replace the ones with the same name.
+    pub fn merge(&mut self, other: &Directives) {
+        if other.frontend != Frontend::Lua {
+            self.frontend = other.frontend;
+        }
+        for (flag, other_flag) in [
+            (&mut self.syntax_only, other.syntax_only),
+            (&mut self.opt, other.opt),
+            (&mut self.format, other.format),
+            (&mut self.pretty, other.pretty),
+            (&mut self.no_comment, other.no_comment),
+            (&mut self.no_cache, other.no_cache),
+            (&mut self.preserve_lines, other.preserve_lines),
+        ] {
+            if other_flag.is_some() {
+                *flag = other_flag;
+            }
+        }
+
+        if let Some(other_params) = &other.params {
+            let params = self.params.get_or_insert_with(Vec::new);
+            for (key, value) in other_params {
+                match params.iter_mut().find(|(k, _)| k == key) {
+                    Some(param) => param.1 = value.clone(),
+                    None => params.push((key.clone(), value.clone())),
+                }
+            }
+        }
+    }
+
+    /// Parse the header of `code`, raising a header error if it is malformed.
+    /// Code without a header gets the default directives.
+    pub fn from_code(code: &str) -> Directives {
+        match Directives::parse(code) {
+            Ok(directives) => directives.unwrap_or_default(),
+            Err(e) => error::raise(TransformStage::Header, Some(header_line(code)), e),
+        }
+    }
+
+    /// Directives of `code` at `file_path`: the project config defaults overridden by the header.
+    /// Raises a header or config error if either of them is invalid.
+    pub fn resolve(code: &str, file_path: &str, config: Option<&ProjectConfig>) -> Directives {
+        let header = Directives::from_code(code);
+        let Some(config) = config else {
+            return header;
+        };
+
+        let mut directives = config
+            .directives_for(file_path)
+            .unwrap_or_else(|e| error::raise(TransformStage::Config, None, e));
+        directives.merge(&header);
+        directives
+            .check()
+            .unwrap_or_else(|e| error::raise(TransformStage::Header, Some(header_line(code)), e));
+        directives
+    }
+
+    /// Names of the enabled directives, in the order of `DIRECTIVES`.
+    pub fn names(&self) -> Vec<&'static str> {
+        DIRECTIVES
+            .iter()
+            .cloned()
+            .filter(|name| match *name {
+                "teal" => self.frontend == Frontend::Teal,
+                "luau" => self.frontend == Frontend::Luau,
+                "syntax-only" => self.syntax_only == Some(true),
+                "opt" => self.opt == Some(true),
+                "format" => self.format == Some(true),
+                "pretty" => self.pretty == Some(true),
+                "no-comment" => self.no_comment == Some(true),
+                "no-cache" => self.no_cache == Some(true),
+                "preserve-lines" => self.preserve_lines == Some(true),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// The param table with the values overridden by env vars of the same name.
+    pub fn param_table(&self) -> Result<Option<HashMap<&str, ParamValue>>, String> {
+        let Some(params) = &self.params else {
+            return Ok(None);
+        };
+
+        let mut map = HashMap::new();
+        for (key, default_value) in params {
+            let current_value = match std::env::var(key) {
+                Ok(value) => ParamValue::parse_override(&value, default_value)
+                    .map_err(|e| format!("Invalid value of env var `{key}`: {e}"))?,
+                Err(_) => default_value.clone(),
+            };
+
+            #[cfg(feature = "debug")]
+            log::debug!("[Directives::param_table] key: {key} default_value: {default_value} current_value: {current_value} match: {}", *default_value == current_value);
+
+            map.insert(key.as_str(), current_value);
+        }
+        Ok(Some(map))
+    }
+}
@@ -0,0 +1,494 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use full_moon::tokenizer::TokenReference;
+use serde::Serialize;
+
+// Error codes, kept stable so that tools can match on them
+pub const E_HEADER: &str = "LJP0001";
+pub const E_CONFIG: &str = "LJP0002";
+pub const E_PARSE: &str = "LJP0101";
+pub const E_TEAL: &str = "LJP0102";
+pub const E_LUAU: &str = "LJP0103";
+pub const E_COMP_TIME_PARAMS: &str = "LJP0201";
+pub const E_COMP_TIME_VARARG: &str = "LJP0202";
+pub const E_COMP_TIME_NAME: &str = "LJP0203";
+pub const E_COMP_TIME_RUNTIME: &str = "LJP0204";
+pub const E_COMP_TIME_LINES: &str = "LJP0205";
+pub const E_INCLUDE: &str = "LJP0301";
+pub const E_ANNOTATION: &str = "LJP0401";
+pub const W_COMP_TIME_ENUM: &str = "LJP0402";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformStage {
+    Read,
+    Config,
+    Header,
+    Parse,
+    Teal,
+    CompTime,
+    Include,
+    Optimize,
+    Inject,
+    Luau,
+    Format,
+    Bytecode,
+    Cache,
+}
+
+impl TransformStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransformStage::Read => "read",
+            TransformStage::Config => "config",
+            TransformStage::Header => "header",
+            TransformStage::Parse => "parse",
+            TransformStage::Teal => "teal",
+            TransformStage::CompTime => "comp_time",
+            TransformStage::Include => "include",
+            TransformStage::Optimize => "optimize",
+            TransformStage::Inject => "inject",
+            TransformStage::Luau => "luau",
+            TransformStage::Format => "format",
+            TransformStage::Bytecode => "bytecode",
+            TransformStage::Cache => "cache",
+        }
+    }
+}
+
+impl fmt::Display for TransformStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TransformError {
+    pub message: String,
+    pub file: String,
+    /// 1-based
+    pub line: Option<usize>,
+    /// 1-based, in characters
+    pub column: Option<usize>,
+    pub stage: TransformStage,
+    /// One of the `E_*` codes
+    pub code: Option<&'static str>,
+    pub hint: Option<String>,
+}
+
+/// Render a codespan-style report with the offending source line, e.g.
+///
+/// ```text
+/// error[LJP0201]: `__LJP:COMP_TIME` has 2 parameters
+///  --> foo.lua:3:29 (comp_time)
+///   |
+/// 3 | function __LJP:COMP_TIME(a, b)
+///   |                             ^
+///   = hint: `__LJP:COMP_TIME` accepts at most one parameter
+/// ```
+fn render(severity: &str, e: &TransformError) -> String {
+    let mut out = match e.code {
+        Some(code) => format!("{severity}[{code}]: {}\n", e.message),
+        None => format!("{severity}: {}\n", e.message),
+    };
+    let location = match (e.line, e.column) {
+        (Some(line), Some(column)) => format!("{}:{line}:{column}", e.file),
+        (Some(line), None) => format!("{}:{line}", e.file),
+        _ => e.file.clone(),
+    };
+    let source_line = e.line.and_then(|line| {
+        let source = std::fs::read_to_string(&e.file).ok()?;
+        source
+            .lines()
+            .nth(line.checked_sub(1)?)
+            .map(|l| l.to_string())
+    });
+
+    let gutter = e.line.map_or(0, |line| line.to_string().len());
+    let pad = " ".repeat(gutter);
+    out.push_str(&format!("{pad}--> {location} ({})\n", e.stage));
+    if let (Some(line), Some(source_line)) = (e.line, source_line) {
+        out.push_str(&format!("{pad} |\n{line} | {source_line}\n"));
+        let caret_pos = match e.column {
+            Some(column) => column.saturating_sub(1),
+            None => source_line.len() - source_line.trim_start().len(),
+        };
+        // Keep the tabs so that the caret lines up
+        let indent: String = source_line
+            .chars()
+            .take(caret_pos)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{pad} | {indent}^\n"));
+    }
+    if let Some(hint) = &e.hint {
+        out.push_str(&format!("{pad} = hint: {hint}\n"));
+    }
+    out
+}
+
+fn to_json(severity: &str, e: &TransformError) -> String {
+    let mut value = serde_json::to_value(e).expect("Failed to serialize error");
+    value["severity"] = severity.into();
+    value.to_string()
+}
+
+impl TransformError {
+    /// An error without a position, e.g. a file that cannot be read.
+    pub fn new(stage: TransformStage, file: &str, message: impl Into<String>) -> Self {
+        TransformError {
+            message: message.into(),
+            file: file.to_string(),
+            line: None,
+            column: None,
+            stage,
+            code: None,
+            hint: None,
+        }
+    }
+
+    /// Multi-line report with the source excerpt, for terminals.
+    pub fn render(&self) -> String {
+        render("error", self)
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json("error", self)
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Same shape as the LuaJIT syntax errors, e.g. `foo.lua:12: [luajit-pro comp_time] ...`
+        match self.line {
+            Some(line) => write!(f, "{}:{}: ", self.file, line)?,
+            None => write!(f, "{}: ", self.file)?,
+        }
+        write!(f, "[luajit-pro {}] {}", self.stage, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, " (hint: {hint})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// A non-fatal issue found while transforming a file.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub stage: TransformStage,
+    pub code: Option<&'static str>,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    fn as_error(&self) -> TransformError {
+        TransformError {
+            message: self.message.clone(),
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            stage: self.stage,
+            code: self.code,
+            hint: self.hint.clone(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        render("warning", &self.as_error())
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json("warning", &self.as_error())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: ", self.file, line)?,
+            None => write!(f, "{}: ", self.file)?,
+        }
+        write!(f, "[luajit-pro {}] warning: {}", self.stage, self.message)
+    }
+}
+
+/// An error or warning which is not bound to a file yet, the file(and the stage unless set) is filled from
+/// the current transform context.
+///
+/// ```ignore
+/// Report::new("`__LJP:COMP_TIME` has 2 parameters")
+///     .code(E_COMP_TIME_PARAMS)
+///     .at_token(&name)
+///     .hint("`__LJP:COMP_TIME` accepts at most one parameter")
+///     .raise()
+/// ```
+#[derive(Debug, Clone)]
+pub struct Report {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+    stage: Option<TransformStage>,
+    code: Option<&'static str>,
+    hint: Option<String>,
+}
+
+impl Report {
+    pub fn new(message: impl Into<String>) -> Self {
+        Report {
+            message: message.into(),
+            line: None,
+            column: None,
+            stage: None,
+            code: None,
+            hint: None,
+        }
+    }
+
+    pub fn stage(mut self, stage: TransformStage) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn at(mut self, line: Option<usize>, column: Option<usize>) -> Self {
+        self.line = line;
+        self.column = column;
+        self
+    }
+
+    /// Point at the start of `token`, only meaningful for tokens of the parsed source.
+    pub fn at_token(self, token: &TokenReference) -> Self {
+        let position = token.token().start_position();
+        self.at(Some(position.line()), Some(position.character()))
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Abort the current transform with this error.
+    pub fn raise(self) -> ! {
+        panic::panic_any(self)
+    }
+
+    /// Report this as a warning, printed right away outside of `collect_diagnostics()`.
+    pub fn warn(self) {
+        let err = current_error(&self);
+        let diagnostic = Diagnostic {
+            message: err.message,
+            file: err.file,
+            line: err.line,
+            column: err.column,
+            stage: err.stage,
+            code: err.code,
+            hint: err.hint,
+        };
+        DIAGNOSTICS.with(|diagnostics| match diagnostics.borrow_mut().as_mut() {
+            Some(diagnostics) => diagnostics.push(diagnostic),
+            None => println!("[luajit_pro_helper] {diagnostic}"),
+        });
+    }
+}
+
+struct Context {
+    file: String,
+    stage: TransformStage,
+}
+
+thread_local! {
+    static CONTEXT_STACK: RefCell<Vec<Context>> = RefCell::new(Vec::new());
+    static CATCH_DEPTH: Cell<usize> = Cell::new(0);
+    static LAST_PANIC: RefCell<Option<TransformError>> = RefCell::new(None);
+    static DIAGNOSTICS: RefCell<Option<Vec<Diagnostic>>> = RefCell::new(None);
+}
+
+/// Push a file onto the transform context, the returned guard pops it again.
+/// Nested transforms (e.g. `__LJP:Include`) push their own file so that errors point at the innermost one.
+pub fn enter_file(file: &str) -> ContextGuard {
+    CONTEXT_STACK.with(|stack| {
+        stack.borrow_mut().push(Context {
+            file: file.to_string(),
+            stage: TransformStage::Read,
+        })
+    });
+    ContextGuard {}
+}
+
+pub struct ContextGuard {}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT_STACK.with(|stack| {
+            stack.borrow_mut().pop();
+        });
+    }
+}
+
+/// Mark the stage the current file is in, used to tag errors raised by plain `panic!`s.
+pub fn set_stage(stage: TransformStage) {
+    CONTEXT_STACK.with(|stack| {
+        if let Some(ctx) = stack.borrow_mut().last_mut() {
+            ctx.stage = stage;
+        }
+    });
+}
+
+/// Abort the current transform with a structured error, see `Report` for errors with a column, code or hint.
+pub fn raise(stage: TransformStage, line: Option<usize>, message: impl Into<String>) -> ! {
+    let report = Report::new(message).stage(stage).at(line, None);
+    match stage {
+        TransformStage::Header => report.code(E_HEADER),
+        TransformStage::Config => report.code(E_CONFIG),
+        _ => report,
+    }
+    .raise()
+}
+
+fn current_error(report: &Report) -> TransformError {
+    CONTEXT_STACK.with(|stack| {
+        let stack = stack.borrow();
+        let (file, ctx_stage) = match stack.last() {
+            Some(ctx) => (ctx.file.clone(), ctx.stage),
+            None => ("?".to_string(), TransformStage::Read),
+        };
+        TransformError {
+            message: report.message.clone(),
+            file,
+            line: report.line,
+            column: report.column,
+            stage: report.stage.unwrap_or(ctx_stage),
+            code: report.code,
+            hint: report.hint.clone(),
+        }
+    })
+}
+
+struct DiagnosticsGuard {
+    prev: Option<Vec<Diagnostic>>,
+}
+
+impl Drop for DiagnosticsGuard {
+    fn drop(&mut self) {
+        DIAGNOSTICS.with(|diagnostics| {
+            let mut diagnostics = diagnostics.borrow_mut();
+            let current = diagnostics.take();
+            let mut prev = self.prev.take();
+            // Nested collections also report to the outer one
+            if let (Some(prev), Some(current)) = (prev.as_mut(), current) {
+                prev.extend(current);
+            }
+            *diagnostics = prev;
+        });
+    }
+}
+
+/// Run `f` and return the warnings reported while it was running.
+pub fn collect_diagnostics<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    let _guard = DiagnosticsGuard {
+        prev: DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().replace(Vec::new())),
+    };
+    let ret = f();
+    let diagnostics =
+        DIAGNOSTICS.with(|diagnostics| diagnostics.borrow().clone().unwrap_or_default());
+    (ret, diagnostics)
+}
+
+fn payload_to_string(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(e) = payload.downcast_ref::<Report>() {
+        e.message.clone()
+    } else if let Some(e) = payload.downcast_ref::<TransformError>() {
+        e.to_string()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+type PanicHook = Box<dyn Fn(&panic::PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+// Number of `catch()`es running on all threads and the hook they replaced, restored by the last one
+static PANIC_HOOK: Mutex<(usize, Option<Arc<PanicHook>>)> = Mutex::new((0, None));
+
+/// The panic hook records the context of the panic before unwinding drops the `ContextGuard`s.
+/// It is only installed while a `catch()` is running, panics are only silenced inside of it and
+/// everything else goes to the previous hook.
+struct PanicHookGuard {}
+
+fn install_panic_hook() -> PanicHookGuard {
+    let mut panic_hook = PANIC_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    panic_hook.0 += 1;
+    if panic_hook.0 == 1 {
+        let prev_hook = Arc::new(panic::take_hook());
+        panic_hook.1 = Some(prev_hook.clone());
+        panic::set_hook(Box::new(move |info| {
+            if CATCH_DEPTH.with(|depth| depth.get()) == 0 {
+                return prev_hook(info);
+            }
+            let payload = info.payload();
+            let err = match payload.downcast_ref::<Report>() {
+                Some(report) => current_error(report),
+                None => current_error(&Report::new(payload_to_string(payload))),
+            };
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(err));
+        }));
+    }
+    PanicHookGuard {}
+}
+
+impl Drop for PanicHookGuard {
+    fn drop(&mut self) {
+        let mut panic_hook = PANIC_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+        panic_hook.0 -= 1;
+        if panic_hook.0 > 0 {
+            return;
+        }
+        if let Some(prev_hook) = panic_hook.1.take() {
+            // Dropping our hook releases its reference to the previous one
+            drop(panic::take_hook());
+            match Arc::try_unwrap(prev_hook) {
+                Ok(prev_hook) => panic::set_hook(prev_hook),
+                Err(prev_hook) => panic::set_hook(Box::new(move |info| prev_hook(info))),
+            }
+        }
+    }
+}
+
+/// Run `f` and turn any panic raised inside of it into a `TransformError`.
+pub fn catch<T>(file: &str, f: impl FnOnce() -> T) -> Result<T, TransformError> {
+    let _hook = install_panic_hook();
+
+    let _guard = enter_file(file);
+    CATCH_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let ret = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCH_DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    ret.map_err(|payload| {
+        LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_else(|| {
+                TransformError::new(
+                    TransformStage::Read,
+                    file,
+                    payload_to_string(payload.as_ref()),
+                )
+            })
+    })
+}
@@ -6,26 +6,135 @@ use darklua_core::generator::LuaGenerator;
 use darklua_core::rules::{Rule, RuleConfiguration, RulePropertyValue};
 use mlua::prelude::*;
 
+use crate::ast_utilis;
+use crate::cache;
+use crate::config::FormatConfig;
+use crate::deps;
+use crate::directives::ParamValue;
+use crate::error::{self, TransformStage};
+
 pub fn lua_dostring(code_name: &str, code: &str) -> (String, bool) {
-    thread_local! {
-        static LUA: UnsafeCell<Lua> = UnsafeCell::new({
-            let lua = unsafe { Lua::unsafe_new() };
+    try_lua_dostring(code_name, code).unwrap_or_else(|e| {
+        error::Report::new(e)
+            .stage(TransformStage::CompTime)
+            .code(error::E_COMP_TIME_RUNTIME)
+            .raise()
+    })
+}
+
+/// Run comp-time code, returns the generated code and whether its lines must be kept.
+pub fn try_lua_dostring(code_name: &str, code: &str) -> Result<(String, bool), String> {
+    eval_comp_time(code_name, None, code)
+}
+
+/// Same as `try_lua_dostring()` for code taken from `file` starting at `first_line`(1-based),
+/// the chunk is named after `file` and padded so that error messages and tracebacks of the
+/// comp-time code report the lines of `file`.
+pub fn try_lua_dostring_at(
+    code_name: &str,
+    file: &str,
+    first_line: usize,
+    code: &str,
+) -> Result<(String, bool), String> {
+    let padded_code = "\n".repeat(first_line.saturating_sub(1)) + code;
+    eval_comp_time(code_name, Some(&format!("@{file}")), &padded_code)
+}
+
+// One comp-time state per thread, the workers of `batch::pregenerate()` never share it
+thread_local! {
+    static COMP_TIME_LUA: UnsafeCell<Lua> = UnsafeCell::new({
+        let lua = unsafe { Lua::unsafe_new() };
 
-            let macro_engine_script = include_str!("lua/macro_engine.lua");
-            let macro_engine_chunk = lua.load(macro_engine_script).set_name("lua/macro_engine.lua");
-            if let Err(e) = macro_engine_chunk.exec() {
-                panic!("Failed to load macro_engine: {}", e);
+        let macro_engine_script = include_str!("lua/macro_engine.lua");
+        let macro_engine_chunk = lua.load(macro_engine_script).set_name("lua/macro_engine.lua");
+        if let Err(e) = macro_engine_chunk.exec() {
+            panic!("Failed to load macro_engine: {}", e);
+        };
+
+        lua
+    });
+
+    // Files of the modules in `package.loaded` of the comp-time state and their hash when they were loaded
+    static REQUIRED_FILES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Unload the modules `require`d by comp-time code once any of their files changed, called before each
+/// transformation. Loaded modules stay in `package.loaded`, without this a file depending on a changed
+/// helper would be regenerated with the old helper and cached under the hash of the new one.
+pub fn refresh_comp_time_modules() {
+    let changed = REQUIRED_FILES.with(|files| {
+        files
+            .borrow()
+            .iter()
+            .any(|(file, hash)| cache::hash_file(file).as_ref() != Some(hash))
+    });
+    if !changed {
+        return;
+    }
+
+    // Every module is unloaded, the unchanged ones may hold values of the changed ones
+    REQUIRED_FILES.with(|files| files.borrow_mut().clear());
+    COMP_TIME_LUA.with(|lua| {
+        let lua = unsafe { &*lua.get() };
+        let unload_modules: LuaFunction = lua.globals().get("_unload_modules").unwrap();
+        unload_modules.call::<()>(()).unwrap();
+    });
+}
+
+/// Globals of the comp-time state replaced by `set_comp_time_params()`, restored on drop(also when
+/// the comp-time code raised an error), so the params of a file are never seen by the next one.
+pub struct CompTimeParams {
+    prev_values: Vec<(String, LuaValue)>,
+}
+
+/// Set `params` as globals of the comp-time state until the returned guard is dropped.
+pub fn set_comp_time_params(params: &[(String, ParamValue)]) -> CompTimeParams {
+    COMP_TIME_LUA.with(|lua| {
+        let lua = unsafe { &*lua.get() };
+        let globals = lua.globals();
+        let mut prev_values = Vec::with_capacity(params.len());
+        for (key, value) in params {
+            let prev_value: LuaValue = globals.raw_get(key.as_str()).unwrap();
+            let value = match value {
+                ParamValue::Bool(b) => LuaValue::Boolean(*b),
+                ParamValue::Int(int) => LuaValue::Number(*int as f64),
+                ParamValue::Float(float) => LuaValue::Number(*float),
+                ParamValue::Str(s) => LuaValue::String(lua.create_string(s).unwrap()),
             };
+            globals.raw_set(key.as_str(), value).unwrap();
+            prev_values.push((key.clone(), prev_value));
+        }
+        CompTimeParams { prev_values }
+    })
+}
 
-            lua
+impl Drop for CompTimeParams {
+    fn drop(&mut self) {
+        COMP_TIME_LUA.with(|lua| {
+            let globals = unsafe { &*lua.get() }.globals();
+            // In reverse order, a key given twice gets back the value it had before the first one
+            for (key, prev_value) in self.prev_values.drain(..).rev() {
+                let _ = globals.raw_set(key, prev_value);
+            }
         });
     }
-    LUA.with(|lua| {
+}
+
+fn eval_comp_time(
+    code_name: &str,
+    chunk_name: Option<&str>,
+    code: &str,
+) -> Result<(String, bool), String> {
+    COMP_TIME_LUA.with(|lua| {
         let lua = unsafe { &mut *lua.get() };
         lua.globals()
             .set("__code_name__", code_name)
             .expect("Failed to set __code_name__");
-        let ret_val = lua.load(code).eval::<mlua::Value>();
+        let chunk = match chunk_name {
+            Some(chunk_name) => lua.load(code).set_name(chunk_name),
+            None => lua.load(code),
+        };
+        let ret_val = chunk.eval::<mlua::Value>();
 
         // Code generated by `output/out/o/outputf/outf/of` will be saved in `output_str`
         let get_output: LuaFunction = lua.globals().get("get_output").unwrap();
@@ -34,29 +143,81 @@ pub fn lua_dostring(code_name: &str, code: &str) -> (String, bool) {
         let check_keep_line: LuaFunction = lua.globals().get("_check_keep_line").unwrap();
         let keep_line: bool = check_keep_line.call::<bool>(()).unwrap();
 
-        (
-            output_str
-                + (match ret_val {
-                    Ok(value) => match value {
-                        mlua::Value::String(s) => s.to_str().unwrap().to_owned(),
-                        mlua::Value::Nil => "".to_owned(),
-                        _ => panic!(
-                            "Expected string bug got {:?}\n----------\n{}\n----------",
-                            value, code
-                        ),
-                    },
-                    Err(err) => panic!(
-                        "Error evaluating lua code, {}\n----------\n{}\n----------",
-                        err, code
-                    ),
-                })
-                .as_str(),
-            keep_line,
-        )
+        // Files read by `require`/`io.open`/`dofile`/`loadfile` at comp-time
+        let take_deps: LuaFunction = lua.globals().get("_take_deps").unwrap();
+        for dep in take_deps.call::<Vec<String>>(()).unwrap() {
+            deps::record(&dep);
+        }
+
+        let required_files: LuaFunction = lua.globals().get("_required_files").unwrap();
+        REQUIRED_FILES.with(|files| {
+            let mut files = files.borrow_mut();
+            for file in required_files.call::<Vec<String>>(()).unwrap() {
+                if !files.contains_key(&file) {
+                    let hash = cache::hash_file(&file).unwrap_or_default();
+                    files.insert(file, hash);
+                }
+            }
+        });
+
+        let take_env_deps: LuaFunction = lua.globals().get("_take_env_deps").unwrap();
+        let env_deps: HashMap<String, mlua::Value> = take_env_deps.call(()).unwrap();
+        for (key, value) in env_deps {
+            let value = match value {
+                mlua::Value::String(s) => Some(s.to_str().unwrap().to_owned()),
+                _ => None,
+            };
+            deps::record_env(&key, value);
+        }
+
+        let ret = match ret_val {
+            Ok(mlua::Value::String(s)) => s.to_str().unwrap().to_owned(),
+            Ok(mlua::Value::Nil) => "".to_owned(),
+            Ok(value) => {
+                return Err(format!(
+                    "Comp-time code must return a string or nil, got {}",
+                    value.type_name()
+                ))
+            }
+            Err(err) => return Err(format!("Error evaluating comp-time code: {err}")),
+        };
+        Ok((output_str + ret.as_str(), keep_line))
     })
 }
 
-pub fn convert_teal_to_lua(input_file_name: &str, syntax_only: bool) -> String {
+/// Line of the first `<file>:<line>:` in an error message of code loaded by `try_lua_dostring_at()`.
+///
+/// Lua shortens long chunk names to `...<tail of the path>`, so a tail of `file` matches too.
+pub fn comp_time_error_line(file: &str, message: &str) -> Option<usize> {
+    let mut rest = message;
+    while let Some(pos) = rest.find(':') {
+        let (before, after) = rest.split_at(pos);
+        let after = &after[1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 && after[digits..].starts_with(':') {
+            let name = before.rsplit(char::is_whitespace).next().unwrap_or(before);
+            let name = name.strip_prefix("...").unwrap_or(name);
+            if !name.is_empty() && file.ends_with(name) {
+                return after[..digits].parse().ok();
+            }
+        }
+        rest = after;
+    }
+    None
+}
+
+/// Position of the first `<file>:<line>:<column>:` in an error message of the Teal compiler.
+fn teal_error_position(file: &str, message: &str) -> (Option<usize>, Option<usize>) {
+    let Some((_, rest)) = message.split_once(&format!("{file}:")) else {
+        return (None, None);
+    };
+    let mut parts = rest.splitn(3, ':');
+    let line = parts.next().and_then(|l| l.parse().ok());
+    let column = parts.next().and_then(|c| c.parse().ok());
+    (line, column)
+}
+
+pub fn convert_teal_to_lua(input_file_name: &str, input: &str, syntax_only: bool) -> String {
     thread_local! {
         static LUA: UnsafeCell<Lua> = UnsafeCell::new({
             let lua = unsafe { Lua::unsafe_new() };
@@ -80,13 +241,46 @@ pub fn convert_teal_to_lua(input_file_name: &str, syntax_only: bool) -> String {
         let lua = unsafe { &mut *lua.get() };
 
         let func: mlua::Function = lua.globals().get("teal_to_lua").unwrap();
-        let lua_code = func
-            .call::<String>((input_file_name, syntax_only))
-            .expect("Failed to call teal_to_lua function");
+        let (lua_code, loaded_files) = func
+            .call::<(String, Vec<String>)>((input_file_name, syntax_only, input))
+            .unwrap_or_else(|e| {
+                let message = e.to_string();
+                let (line, column) = teal_error_position(input_file_name, &message);
+                error::Report::new(message)
+                    .stage(TransformStage::Teal)
+                    .code(error::E_TEAL)
+                    .at(line, column)
+                    .raise()
+            });
+        for file in loaded_files {
+            deps::record(&file);
+        }
         lua_code
     })
 }
 
+/// Compile the generated code into bytecode with the LuaJIT vendored by mlua, `strip` drops the debug info.
+pub fn compile_to_bytecode(chunk_name: &str, code: &str, strip: bool) -> Vec<u8> {
+    thread_local! {
+        static LUA: Lua = Lua::new();
+    }
+    LUA.with(
+        |lua| match lua.load(code).set_name(chunk_name).into_function() {
+            Ok(func) => func.dump(strip),
+            Err(e) => error::raise(
+                TransformStage::Bytecode,
+                None,
+                format!("Failed to compile the generated code => {e}"),
+            ),
+        },
+    )
+}
+
+/// Error handler of the darklua rules: `.unwrap_or_else(rule_failed(stage, "remove ..."))`.
+fn rule_failed<E: std::fmt::Display>(stage: TransformStage, what: &'static str) -> impl FnOnce(E) {
+    move |e| error::raise(stage, None, format!("Failed to {what} => {e}"))
+}
+
 pub fn convert_luau_to_lua(input: &str) -> String {
     let resources: darklua_core::Resources = darklua_core::Resources::from_memory();
     let context = darklua_core::rules::ContextBuilder::new(".", &resources, input).build();
@@ -94,39 +288,48 @@ pub fn convert_luau_to_lua(input: &str) -> String {
         .preserve_tokens()
         .parse(input)
         .unwrap_or_else(|error| {
-            panic!(
-                "[convert_luau_to_lua] darklua_core could not parse content: {:?}\ncontent:\n{}",
-                error, input
-            );
+            error::Report::new(format!("Could not parse the Luau code: {error:?}"))
+                .stage(TransformStage::Luau)
+                .code(error::E_LUAU)
+                .raise();
         });
 
     darklua_core::rules::RemoveCompoundAssignment::default()
         .process(&mut block, &context)
-        .expect("Failed to remove compound assignment");
+        .unwrap_or_else(rule_failed(
+            TransformStage::Luau,
+            "remove compound assignment",
+        ));
     darklua_core::rules::RemoveFloorDivision::default()
         .process(&mut block, &context)
-        .expect("Failed to remove floor division");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove floor division"));
     darklua_core::rules::RemoveTypes::default()
         .process(&mut block, &context)
-        .expect("Failed to remove types");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove types"));
     darklua_core::rules::RemoveIfExpression::default()
         .process(&mut block, &context)
-        .expect("Failed to remove if expression");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove if expression"));
     darklua_core::rules::RemoveContinue::default()
         .process(&mut block, &context)
-        .expect("Failed to remove continue");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove continue"));
     darklua_core::rules::RemoveInterpolatedString::default()
         .process(&mut block, &context)
-        .expect("Failed to remove interpolated string");
+        .unwrap_or_else(rule_failed(
+            TransformStage::Luau,
+            "remove interpolated string",
+        ));
     darklua_core::rules::RemoveUnusedIfBranch::default()
         .process(&mut block, &context)
-        .expect("Failed to remove unused if branches");
+        .unwrap_or_else(rule_failed(
+            TransformStage::Luau,
+            "remove unused if branches",
+        ));
     darklua_core::rules::RemoveEmptyDo::default()
         .process(&mut block, &context)
-        .expect("Failed to remove empty do");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove empty do"));
     darklua_core::rules::RemoveUnusedVariable::default()
         .process(&mut block, &context)
-        .expect("Failed to remove unused variables");
+        .unwrap_or_else(rule_failed(TransformStage::Luau, "remove unused variables"));
 
     let mut generator = darklua_core::generator::TokenBasedLuaGenerator::new(input);
     generator.write_block(&block);
@@ -135,33 +338,61 @@ pub fn convert_luau_to_lua(input: &str) -> String {
     lua_code
 }
 
-pub fn inject_global_vals(input: &str, input_param_table: HashMap<&str, String>) -> String {
+/// Inject the param values as globals, unless `preserve_lines` is set the dead branches are removed afterwards.
+pub fn inject_global_vals(
+    input: &str,
+    input_param_table: HashMap<&str, ParamValue>,
+    preserve_lines: bool,
+) -> String {
     let resources: darklua_core::Resources = darklua_core::Resources::from_memory();
     let context = darklua_core::rules::ContextBuilder::new(".", &resources, input).build();
     let mut block = darklua_core::Parser::default()
         .preserve_tokens()
         .parse(input)
         .unwrap_or_else(|error| {
-            panic!(
-                "[inject_global_vals] darklua_core could not parse content: {:?}\ncontent:\n{}",
-                error, input
+            error::raise(
+                TransformStage::Inject,
+                None,
+                format!(
+                    "[inject_global_vals] darklua_core could not parse content: {:?}\ncontent:\n{}",
+                    error, input
+                ),
             );
         });
 
     for (key, value) in input_param_table {
-        darklua_core::rules::InjectGlobalValue::boolean(key, value == "true" || value == "1")
+        let rule = match value {
+            ParamValue::Bool(b) => darklua_core::rules::InjectGlobalValue::boolean(key, b),
+            ParamValue::Int(int) => darklua_core::rules::InjectGlobalValue::number(key, int as f64),
+            ParamValue::Float(float) => darklua_core::rules::InjectGlobalValue::number(key, float),
+            ParamValue::Str(s) => darklua_core::rules::InjectGlobalValue::string(key, s),
+        };
+        rule.process(&mut block, &context).unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Inject,
+                None,
+                format!("Failed to inject the param `{key}` => {e}"),
+            )
+        });
+    }
+    // Removing statements would move the following code to other lines
+    if !preserve_lines {
+        darklua_core::rules::RemoveUnusedIfBranch::default()
+            .process(&mut block, &context)
+            .unwrap_or_else(rule_failed(
+                TransformStage::Inject,
+                "remove unused if branch",
+            ));
+        darklua_core::rules::RemoveEmptyDo::default()
+            .process(&mut block, &context)
+            .unwrap_or_else(rule_failed(TransformStage::Inject, "remove empty do"));
+        darklua_core::rules::RemoveUnusedVariable::default()
             .process(&mut block, &context)
-            .expect("Failed to inject global value");
+            .unwrap_or_else(rule_failed(
+                TransformStage::Inject,
+                "remove unused variables",
+            ));
     }
-    darklua_core::rules::RemoveUnusedIfBranch::default()
-        .process(&mut block, &context)
-        .expect("Failed to remove unused if branch");
-    darklua_core::rules::RemoveEmptyDo::default()
-        .process(&mut block, &context)
-        .expect("Failed to remove empty do");
-    darklua_core::rules::RemoveUnusedVariable::default()
-        .process(&mut block, &context)
-        .expect("Failed to remove unused variables");
 
     let mut generator = darklua_core::generator::TokenBasedLuaGenerator::new(input);
     generator.write_block(&block);
@@ -177,7 +408,7 @@ pub fn remove_lua_comments(input: &str) -> String {
         .preserve_tokens()
         .parse(input)
         .unwrap_or_else(|error| {
-            panic!("could not parse content: {:?}\ncontent:\n{}\norigin_code:\n----------------------\n{input}\n----------------------", error, input);
+            error::raise(TransformStage::Format, None, format!("could not parse content: {:?}\ncontent:\n{}\norigin_code:\n----------------------\n{input}\n----------------------", error, input));
         });
 
     let mut rule = darklua_core::rules::RemoveComments::default();
@@ -199,7 +430,7 @@ pub fn remove_lua_comments(input: &str) -> String {
     .expect("Failed to configure rule");
 
     rule.process(&mut block, &context)
-        .expect("rule should suceed");
+        .unwrap_or_else(rule_failed(TransformStage::Format, "remove comments"));
     let mut generator = darklua_core::generator::TokenBasedLuaGenerator::new(input);
     generator.write_block(&block);
     let lua_code = generator.into_string();
@@ -207,11 +438,10 @@ pub fn remove_lua_comments(input: &str) -> String {
     lua_code
 }
 
-pub fn format_lua_code(input: &str) -> String {
-    let ast = full_moon::parse(&input).expect("Failed to parse generated AST");
-    let mut cfg = stylua_lib::Config::new();
-    cfg.column_width = 240;
-    let ret_ast =
-        stylua_lib::format_ast(ast, cfg, None, stylua_lib::OutputVerification::None).unwrap();
+pub fn format_lua_code(input: &str, format_config: &FormatConfig) -> String {
+    let ast = ast_utilis::parse_lua(input);
+    let cfg = format_config.to_stylua();
+    let ret_ast = stylua_lib::format_ast(ast, cfg, None, stylua_lib::OutputVerification::None)
+        .unwrap_or_else(|e| error::raise(TransformStage::Format, None, e.to_string()));
     ret_ast.to_string()
 }
@@ -1,22 +1,50 @@
 #![allow(unused_imports)]
 
+mod aot;
 mod ast_utilis;
+mod batch;
+mod build_cache;
+mod cache;
+mod cache_backend;
+mod capi;
+mod config;
+mod deps;
+mod directives;
+mod error;
 mod lang_utils;
 mod lua_optimizer;
 mod lua_transformer;
+mod pipeline;
+mod source_map;
+mod watch;
 
 use std::collections::HashMap;
 use std::ffi::{c_char, CStr, CString};
 use std::fs::File;
 use std::io::{BufRead, Write};
-use std::path::Path;
-use std::time::{Duration, Instant, SystemTime};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "print-time")]
+use std::time::Instant;
 
-use fslock::LockFile;
+use cache_backend::BackendSpec;
+use config::ProjectConfig;
 use full_moon::visitors::VisitorMut;
 use lazy_static::lazy_static;
-use lua_optimizer::LuaOptimizer;
-use lua_transformer::LuaTransformer;
+
+pub use aot::{build_tree, AotReport};
+pub use batch::{collect_files, pregenerate, BatchReport};
+pub use build_cache::{BuildCache, CacheEntryStatus};
+pub use cache::CacheStats;
+pub use cache_backend::{write_bundle, BundleBackend, CacheBackend, FsBackend, MemoryBackend};
+pub use capi::*;
+pub use config::{FormatConfig, IndentType, QuoteStyle};
+pub use directives::{header_end, header_start, Directives, Frontend, ParamValue, DIRECTIVES};
+pub use error::{Diagnostic, TransformError, TransformStage};
+pub use lua_optimizer::OPTIMIZER_PASSES;
+pub use pipeline::{CachePolicy, Pipeline, TransformOptions, TransformOutput};
+pub use source_map::SourceMap;
+pub use watch::{WatchEvent, Watcher};
 
 const OUTPUT_DIR: &'static str = ".luajit_pro";
 
@@ -41,10 +69,8 @@ lazy_static! {
             v == "1"
         })
         .unwrap_or(false);
-    static ref BUILD_CACHE_DIR: String = {
-        let default_dir = format!("{}/build_cache", OUTPUT_DIR);
-        std::env::var("LJP_OUT_DIR").unwrap_or(default_dir)
-    };
+    static ref ENV_OUT_DIR: Option<String> = std::env::var("LJP_OUT_DIR").ok();
+    static ref ENV_CACHE_BACKEND: Option<String> = std::env::var("LJP_CACHE_BACKEND").ok();
     static ref ENV_NO_OPT: bool = std::env::var("LJP_NO_OPT")
         .map(|v| {
             let standard_font = figlet_rs::FIGfont::standard().unwrap();
@@ -52,196 +78,503 @@ lazy_static! {
             v == "1"
         })
         .unwrap_or(false);
+    static ref ENV_BYTECODE: Option<bool> = std::env::var("LJP_BYTECODE").ok().map(|v| v == "1");
+    static ref ENV_BYTECODE_STRIP: Option<bool> =
+        std::env::var("LJP_BYTECODE_STRIP").ok().map(|v| v == "1");
+    // Config file of the directories checked by `ljp_file_matches_config()`, `None` if they have none
+    static ref CONFIG_DIRS: Mutex<HashMap<PathBuf, Option<PathBuf>>> = Mutex::new(HashMap::new());
 }
 
-fn get_mtime(file_path: &str) -> SystemTime {
-    match std::fs::metadata(file_path) {
-        Ok(metadata) => match metadata.modified() {
-            Ok(mtime) => mtime,
-            Err(e) => panic!("Failed to get modified time => {}", e.to_string()),
-        },
-        Err(e) => panic!("Failed to get metadata => {}", e.to_string()),
-    }
+thread_local! {
+    // Error message of the last failed `transform_lua()` call on this thread, read by `ljp_last_error()`
+    static LAST_ERROR: std::cell::RefCell<Option<CString>> = std::cell::RefCell::new(None);
 }
 
+/// The param table of the header with env overrides applied, raising a header error on invalid values.
 #[inline]
-fn parse_param_table(line: &str) -> (Option<HashMap<&str, String>>, bool) {
-    let start = line.find('{');
-    let end = line.rfind('}');
-    if !(start.is_some() && end.is_some()) {
-        return (None, false);
-    }
+fn header_params(directives: &Directives) -> Option<HashMap<&str, ParamValue>> {
+    directives
+        .param_table()
+        .unwrap_or_else(|e| error::raise(TransformStage::Header, Some(1), e))
+}
 
-    let content = &line[start.unwrap() + 1..end.unwrap()];
-    let mut map = HashMap::new();
-    let mut need_rebuild = false;
-    for kv in content.split(',') {
-        if let Some((k, v)) = kv.split_once('=') {
-            let key = k.trim();
-            let default_value = v.trim();
-            let current_value = std::env::var(key).unwrap_or(default_value.to_string());
+/// Directives of a file: the project config defaults overridden by its header.
+fn resolve_directives(code: &str, lua_file_path: &str) -> (Directives, Option<Arc<ProjectConfig>>) {
+    error::set_stage(TransformStage::Config);
+    let config = config::load_for(lua_file_path);
+    error::set_stage(TransformStage::Header);
+    let directives = Directives::resolve(code, lua_file_path, config.as_deref());
+    (directives, config)
+}
 
-            #[cfg(feature = "debug")]
-            log::debug!("[parse_param_table] key: {k} default_value: {default_value} current_value: {current_value} match: {}", default_value == current_value);
+/// Cache dir of the sources which belong to no project: `$XDG_CACHE_HOME/luajit_pro/build_cache`.
+/// A single one for all of them, the entries are named after the path of their source, so that
+/// the cache commands run outside of a project see every entry.
+fn user_cache_dir() -> Option<String> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(
+        cache_home
+            .join("luajit_pro")
+            .join("build_cache")
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
 
-            if !need_rebuild {
-                need_rebuild = default_value != current_value;
-            }
-            assert!(
-                matches!(current_value.as_str(), "true" | "false" | "0" | "1"),
-                "Invalid value, key: {}, value: {}",
-                key,
-                current_value
-            );
-            map.insert(key, current_value);
-        }
+/// Cache dir of `lua_file_path`, whatever the working directory is:
+/// 1. `LJP_OUT_DIR`
+/// 2. the `cache_dir` of the project config
+/// 3. `.luajit_pro/build_cache` in the project root, i.e. the directory of ljp.toml or the repository root
+/// 4. `$XDG_CACHE_HOME/luajit_pro/build_cache`(`~/.cache` by default), shared by the files outside of a project
+/// 5. `.luajit_pro/build_cache` in the working directory if there is no home directory either
+fn cache_dir_for(config: Option<&ProjectConfig>, lua_file_path: &str) -> String {
+    if let Some(dir) = ENV_OUT_DIR.as_ref() {
+        return dir.clone();
+    }
+    if let Some(dir) = config.and_then(|config| config.cache_dir()) {
+        return dir;
+    }
+    let project_root = config
+        .map(|config| config.root().to_path_buf())
+        .or_else(|| config::find_vcs_root(lua_file_path));
+    if let Some(root) = project_root {
+        return root
+            .join(OUTPUT_DIR)
+            .join("build_cache")
+            .to_string_lossy()
+            .into_owned();
     }
-    (Some(map), need_rebuild)
+    user_cache_dir().unwrap_or_else(|| format!("{}/build_cache", OUTPUT_DIR))
 }
 
-#[inline]
-fn serialize_param_table(param_table: Option<HashMap<&str, String>>) -> String {
-    let mut result = String::from("{");
+/// `LJP_CACHE_BACKEND` if set, otherwise the `cache_backend` of the project config, otherwise the files on disk.
+fn cache_backend_for(config: Option<&ProjectConfig>) -> Arc<dyn CacheBackend> {
+    let spec = match ENV_CACHE_BACKEND.as_deref() {
+        Some(value) => BackendSpec::parse(value).map(Some),
+        None => Ok(config.and_then(|config| config.cache_backend())),
+    }
+    .unwrap_or_else(|e| error::raise(TransformStage::Config, None, e))
+    .unwrap_or(BackendSpec::Fs);
+    cache_backend::open(&spec).unwrap_or_else(|e| {
+        error::raise(
+            TransformStage::Cache,
+            None,
+            format!("Failed to open the {spec:?} cache backend => {e}"),
+        )
+    })
+}
 
-    for (key, value) in param_table.unwrap() {
-        result.push_str(&format!("{} = {}, ", key, value));
+/// `Some(strip)` if the loader gets bytecode, `LJP_BYTECODE`/`LJP_BYTECODE_STRIP` override the `[bytecode]` table of the project config.
+fn bytecode_strip(config: Option<&ProjectConfig>) -> Option<bool> {
+    let bytecode = config.map(|c| c.bytecode.clone()).unwrap_or_default();
+    if !ENV_BYTECODE.unwrap_or(bytecode.enabled) {
+        return None;
     }
+    Some(ENV_BYTECODE_STRIP.unwrap_or(bytecode.strip))
+}
 
-    result.pop();
-    result.pop();
-    result.push_str("}");
+#[inline]
+fn serialize_param_table(param_table: &HashMap<&str, ParamValue>) -> String {
+    let params: Vec<String> = param_table
+        .iter()
+        .map(|(key, value)| format!("{} = {}", key, value))
+        .collect();
+    format!("{{{}}}", params.join(", "))
+}
 
-    result
+/// The header of a source file with its param table replaced by the values the file was transformed with,
+/// a table spanning several lines is padded so that the number of lines does not change.
+fn rewrite_header(header: &str, param_table: &Option<HashMap<&str, ParamValue>>) -> String {
+    let (Some(param_table), Some(start), Some(end)) =
+        (param_table, header.find('{'), header.rfind('}'))
+    else {
+        return header.to_string();
+    };
+    format!(
+        "{}{}{}{}",
+        &header[..start],
+        serialize_param_table(param_table),
+        "\n".repeat(header[start..end].matches('\n').count()),
+        &header[end + 1..]
+    )
 }
 
 pub fn transform_lua_code(
     code: &str,
     lua_file_path: &str,
-    param_table: Option<HashMap<&str, String>>,
+    param_table: Option<HashMap<&str, ParamValue>>,
 ) -> String {
-    #[cfg(feature = "debug")]
-    let debug_prefix = format!("[transform_lua_code] <{lua_file_path}>");
+    transform_lua_code_with_map(code, lua_file_path, param_table).0
+}
 
-    let first_line = code.lines().next().unwrap_or("");
+/// Same as `transform_lua_code()` but also returns the line mapping from the generated code back to the sources.
+pub fn transform_lua_code_with_map(
+    code: &str,
+    lua_file_path: &str,
+    param_table: Option<HashMap<&str, ParamValue>>,
+) -> (String, SourceMap) {
+    let mut pipeline = legacy_pipeline();
+    pipeline.param_override = param_table.map(|param_table| {
+        param_table
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+    });
+    pipeline.run(code, lua_file_path)
+}
 
-    let final_code = if first_line.contains("teal") {
-        assert!(
-            !first_line.contains("luau"),
-            "Cannot use both luau and teal"
-        );
-        let lua_code =
-            lang_utils::convert_teal_to_lua(lua_file_path, first_line.contains("syntax-only"))
-                .replace("bit32", "bit");
-        lua_code
-    } else {
-        code.to_string()
-    };
+/// The pipeline of the loader entry points, configured by the project config and the `LJP_*` env vars.
+fn legacy_pipeline() -> Pipeline {
+    TransformOptions::new()
+        .project_config(true)
+        .env_overrides(true)
+        .disable_optimizer(*ENV_NO_OPT)
+        .build()
+}
 
-    let ast = full_moon::parse(&final_code).unwrap();
+/// Same as `transform_lua_code()` but any failure is returned as a `TransformError` instead of panicking.
+pub fn try_transform_lua_code(
+    code: &str,
+    lua_file_path: &str,
+    param_table: Option<HashMap<&str, ParamValue>>,
+) -> Result<String, TransformError> {
+    error::catch(lua_file_path, || {
+        transform_lua_code(code, lua_file_path, param_table)
+    })
+}
 
-    let mut transformer = LuaTransformer::new();
-    transformer.file_path = Some((lua_file_path.to_string()).to_string());
-    transformer.input_param_list = {
-        let mut input_param_list = Vec::new();
-        if let Some(param_table) = param_table.clone() {
-            for (key, value) in param_table {
-                input_param_list.push((key.to_owned(), value));
-            }
-            if input_param_list.len() == 0 {
-                None
-            } else {
-                Some(input_param_list)
-            }
-        } else {
-            None
-        }
-    };
-    let mut new_ast = transformer.visit_ast(ast);
+/// Transform a file for the LuaJIT loader, prefer `ljp_transform_file()` in new code.
+///
+/// Returns NULL if the transformation failed, the error message can then be fetched by `ljp_last_error()`.
+/// The returned string must be released by `ljp_free_string()`.
+#[no_mangle]
+pub extern "C" fn transform_lua(file_path: *const c_char) -> *const c_char {
+    let c_str = unsafe { CStr::from_ptr(file_path) };
+    let lua_file_path = c_str.to_string_lossy();
+
+    let ret = error::catch(&lua_file_path, || {
+        let content = transform_lua_file(&lua_file_path);
+        // The C string would end at the NUL byte, the loader must not get truncated code
+        CString::new(content).unwrap_or_else(|e| {
+            let line = e.as_bytes()[..e.nul_position()]
+                .iter()
+                .filter(|&&c| c == b'\n')
+                .count()
+                + 1;
+            error::Report::new(format!(
+                "Line {line} of the generated code contains a NUL byte"
+            ))
+            .hint("Write it as `\\0` in string literals")
+            .raise()
+        })
+    });
+    match ret {
+        Ok(content) => content.into_raw(),
+        Err(e) => {
+            #[cfg(feature = "debug")]
+            log::debug!("[transform_lua] <{lua_file_path}> failed: {e}");
 
-    if first_line.contains("opt") && !*ENV_NO_OPT {
-        let mut optimizer = LuaOptimizer::new();
-        let neww_ast = full_moon::parse(&new_ast.to_string())
-            .expect(&format!("Failed to parse: <<<{}>>>", new_ast.to_string()));
-        new_ast = optimizer.visit_ast(neww_ast);
+            set_last_error(&e);
+            std::ptr::null()
+        }
     }
+}
 
-    let mut new_content = new_ast.to_string();
+fn set_last_error(e: &TransformError) {
+    LAST_ERROR.with(|last| {
+        *last.borrow_mut() = Some(CString::new(e.to_string().replace('\0', "")).unwrap())
+    });
+}
 
-    if let Some(param_table) = param_table {
-        new_content = lang_utils::inject_global_vals(&new_content, param_table);
+/// Convert a LuaJIT chunk name into the name used by the transformer,
+/// e.g. `@foo/bar.lua` => `foo/bar.lua`, `=stdin` => `stdin`, `local a = 1` => `[string "local a = 1"]`.
+fn chunk_name_to_path(chunk_name: &str) -> String {
+    match chunk_name
+        .strip_prefix('@')
+        .or_else(|| chunk_name.strip_prefix('='))
+    {
+        Some(name) => name.to_string(),
+        None => format!("[string \"{}\"]", chunk_name.lines().next().unwrap_or("")),
     }
+}
 
-    if first_line.contains("luau") {
-        assert!(
-            !first_line.contains("teal"),
-            "Cannot use both luau and teal"
-        );
-        new_content = lang_utils::convert_luau_to_lua(&new_content);
+/// Transform an in-memory chunk loaded through `load`/`loadstring`/`luaL_loadbufferx`.
+/// Chunks without the `luajit-pro` header are returned unchanged, string chunks are never cached.
+pub fn transform_lua_string(chunk_name: &str, code: &str) -> String {
+    if !directives::starts_with_header(code) {
+        return code.to_string();
     }
 
-    if first_line.contains("pretty") {
-        #[cfg(feature = "debug")]
-        log::debug!("{debug_prefix} pretty");
+    let lua_file_path = chunk_name_to_path(chunk_name);
+    let (directives, _) = resolve_directives(code, &lua_file_path);
+    transform_lua_code(code, &lua_file_path, header_params(&directives))
+}
 
-        // pretty == no-comment + format
-        new_content = lang_utils::format_lua_code(&lang_utils::remove_lua_comments(&new_content));
-    } else {
-        if first_line.contains("no-comment") {
-            #[cfg(feature = "debug")]
-            log::debug!("{debug_prefix} no-comment");
+/// Release a string returned by `transform_lua()`.
+#[no_mangle]
+pub extern "C" fn ljp_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
 
-            new_content = lang_utils::remove_lua_comments(&new_content);
-        }
+/// Error message of the last failed `transform_lua()` call on the current thread, NULL if there is none.
+/// The returned pointer stays valid until the next failed call on the same thread.
+#[no_mangle]
+pub extern "C" fn ljp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
 
-        if first_line.contains("format") {
-            #[cfg(feature = "debug")]
-            log::debug!("{debug_prefix} format");
+/// Key of the cache entry of the loader entry points: the resolved directives, params, format and optimizer
+/// settings(the same flags as a `Pipeline`) and whether bytecode is generated.
+fn cache_key(
+    pipeline: &Pipeline,
+    lua_file_path: &str,
+    content: &str,
+    settings: &pipeline::Settings,
+    bytecode: Option<bool>,
+) -> String {
+    let mut flags = pipeline.key_flags(settings);
+    flags.push(("bytecode", format!("{bytecode:?}")));
+    cache::compute_key(lua_file_path, content, &settings.param_table(), &flags)
+}
+
+/// Whether the file is transformed even without a header, i.e. it matches a `[[files]]` entry of its project config.
+/// A broken config also counts as a match so that its error is reported when loading the file.
+pub fn file_matches_config(lua_file_path: &str) -> bool {
+    match config::project_config(lua_file_path) {
+        Ok(Some(config)) => config.matches(lua_file_path),
+        Ok(None) => false,
+        Err(_) => true,
+    }
+}
+
+/// Checked by the LuaJIT loader for the files without the luajit-pro header, i.e. for every plain Lua
+/// module it loads. The config file of each directory(or the lack of one) is looked up once for the
+/// lifetime of the process, the loads from it skip the walk up to the filesystem root. The config itself
+/// is reloaded when it changes, a config added later is only seen by the next process.
+#[no_mangle]
+pub extern "C" fn ljp_file_matches_config(file_path: *const c_char) -> c_char {
+    let c_str = unsafe { CStr::from_ptr(file_path) };
+    let lua_file_path = c_str.to_string_lossy();
+
+    let dir = Path::new(lua_file_path.as_ref())
+        .parent()
+        .map(|dir| match std::env::current_dir() {
+            Ok(cwd) if dir.is_relative() => cwd.join(dir),
+            _ => dir.to_path_buf(),
+        });
+    let Some(dir) = dir else {
+        return file_matches_config(&lua_file_path) as c_char;
+    };
 
-            new_content = lang_utils::format_lua_code(&new_content);
+    let cached = CONFIG_DIRS.lock().unwrap().get(&dir).cloned();
+    let config_path = match cached {
+        // A removed config is looked up again
+        Some(config_path) if config_path.as_ref().is_none_or(|path| path.is_file()) => config_path,
+        _ => {
+            let config_path = config::find_config_file(&lua_file_path);
+            CONFIG_DIRS.lock().unwrap().insert(dir, config_path.clone());
+            config_path
         }
+    };
+
+    match config_path.map(|config_path| config::load_config(&config_path)) {
+        Some(Ok(config)) => config.matches(&lua_file_path) as c_char,
+        Some(Err(_)) => 1,
+        None => 0,
     }
+}
 
-    new_content
+/// Enabled directives and params(with env overrides applied) of a file starting with the header `header`,
+/// including the defaults of the project config.
+pub fn explain_header(
+    lua_file_path: &str,
+    header: &str,
+) -> Result<(Vec<&'static str>, Vec<(String, String)>), TransformError> {
+    error::catch(lua_file_path, || {
+        let (directives, _) = resolve_directives(header, lua_file_path);
+        let mut params: Vec<(String, String)> = header_params(&directives)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        params.sort();
+        (directives.names(), params)
+    })
 }
 
-#[no_mangle]
-pub fn transform_lua(file_path: *const c_char) -> *const c_char {
-    #[cfg(feature = "print-time")]
-    let start = Instant::now();
+/// Transform a file without going through the build cache.
+pub fn expand_lua_file(lua_file_path: &str) -> Result<String, TransformError> {
+    error::catch(lua_file_path, || {
+        error::set_stage(TransformStage::Read);
+        let content = std::fs::read_to_string(lua_file_path).unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Read,
+                None,
+                format!("Failed to read file => {}", e),
+            )
+        });
+        let (directives, _) = resolve_directives(&content, lua_file_path);
+        transform_lua_code(&content, lua_file_path, header_params(&directives))
+    })
+}
 
-    let c_str = unsafe { CStr::from_ptr(file_path) };
-    let lua_file_path = c_str.to_str().unwrap();
+/// The build cache directory of the project in the current directory.
+pub fn cache_dir() -> String {
+    BuildCache::current().dir
+}
 
-    let first_line = {
-        let file =
-            File::open(lua_file_path).expect(&format!("Failed to open file => {}", lua_file_path));
-        let mut reader = std::io::BufReader::new(file);
-        let mut first_line = String::new();
-        reader.read_line(&mut first_line).unwrap();
+/// The cache backend of the project in the current directory.
+pub fn cache_backend() -> Arc<dyn CacheBackend> {
+    BuildCache::current().backend
+}
 
-        first_line
-    };
+/// Entries of the build cache of the project in the current directory, see `BuildCache::entries()`.
+pub fn cache_entries() -> Vec<CacheEntryStatus> {
+    BuildCache::current().entries()
+}
 
-    let no_cache = first_line.contains("no-cache") || *ENV_NO_CACHE || *ENV_GEN_ONLY;
+/// Hits and misses of the build cache in this process.
+pub fn cache_stats() -> CacheStats {
+    cache::stats()
+}
 
-    let cached_file = format!(
-        "{}/{}",
-        *BUILD_CACHE_DIR,
-        Path::new(lua_file_path)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
+/// Files the cache entry of `lua_file_path` was generated from besides the file itself
+/// (includes, the project config, files read at comp-time), empty if there is no entry.
+fn cached_dependencies(lua_file_path: &str) -> Vec<String> {
+    let config = config::project_config(lua_file_path).ok().flatten();
+    let entry = cache::entry_path(
+        &cache_dir_for(config.as_deref(), lua_file_path),
+        lua_file_path,
     );
+    cache::read_meta(cache_backend_for(config.as_deref()).as_ref(), &entry)
+        .map(|meta| meta.deps.into_iter().map(|dep| dep.path).collect())
+        .unwrap_or_default()
+}
 
-    #[cfg(feature = "debug")]
-    let debug_prefix = format!("[transform_lua] <{lua_file_path}>");
+/// Remove every file of the build cache, returns the number of removed files.
+pub fn clean_cache() -> std::io::Result<usize> {
+    BuildCache::current().clean()
+}
+
+/// Remove the stale entries and the ones whose source no longer exists, returns the number of removed entries.
+/// Temp and lock files left behind by crashed processes are removed as well.
+pub fn prune_cache() -> usize {
+    BuildCache::current().prune()
+}
+
+/// Pack the build cache of the project in the current directory into the read-only bundle `out`,
+/// see `cache_backend = "bundle"` of ljp.toml. Returns the number of packed files.
+pub fn bundle_cache(out: &str) -> std::io::Result<usize> {
+    BuildCache::current().bundle(out)
+}
+
+/// Compile `code` and write the bytecode next to the cache entry, unless the backend is read-only.
+fn store_bytecode(
+    backend: &dyn CacheBackend,
+    cached_file: &str,
+    lua_file_path: &str,
+    code: &str,
+    strip: bool,
+) -> Vec<u8> {
+    error::set_stage(TransformStage::Bytecode);
+    let bytecode = lang_utils::compile_to_bytecode(&format!("@{lua_file_path}"), code, strip);
+    if !backend.read_only() {
+        backend
+            .write(&cache::bytecode_path(cached_file), &bytecode)
+            .unwrap_or_else(|e| {
+                error::raise(
+                    TransformStage::Cache,
+                    None,
+                    format!("Failed to write bytecode => {cached_file}, {e}"),
+                )
+            });
+    }
+    bytecode
+}
+
+/// The generated code of a file and its bytecode, see `transform_lua_entry()`.
+struct Transformed {
+    code: String,
+    /// `None` unless requested and enabled(see `[bytecode]` of ljp.toml)
+    bytecode: Option<Vec<u8>>,
+}
+
+/// The cached code of `lua_file_path` if its entry is valid, writing the bytecode if it is enabled but missing.
+/// The bytecode is read only if `load_bytecode` is set.
+fn load_cached(
+    backend: &dyn CacheBackend,
+    cached_file: &str,
+    cache_key: &str,
+    lua_file_path: &str,
+    bytecode: Option<bool>,
+    load_bytecode: bool,
+) -> Option<Transformed> {
+    let code = cache::load(backend, cached_file, cache_key)?;
+    let chunk = bytecode.map(|strip| {
+        let bytecode_path = cache::bytecode_path(cached_file);
+        let cached = if load_bytecode {
+            backend.read(&bytecode_path)
+        } else {
+            // Not loaded, only written if missing
+            backend.exists(&bytecode_path).then(Vec::new)
+        };
+        cached.unwrap_or_else(|| store_bytecode(backend, cached_file, lua_file_path, &code, strip))
+    });
+    Some(Transformed {
+        code,
+        bytecode: chunk.filter(|_| load_bytecode),
+    })
+}
+
+/// The chunk handed to the LuaJIT loader: the bytecode if `allow_bytecode` and enabled(see `[bytecode]` of
+/// ljp.toml), the generated code otherwise.
+fn load_lua_file(lua_file_path: &str, allow_bytecode: bool) -> Vec<u8> {
+    let transformed = transform_lua_entry(lua_file_path, allow_bytecode && !*ENV_GEN_ONLY);
+    match transformed.bytecode {
+        Some(bytecode) => bytecode,
+        None => transformed.code.into_bytes(),
+    }
+}
+
+fn transform_lua_file(lua_file_path: &str) -> String {
+    transform_lua_entry(lua_file_path, false).code
+}
+
+/// Transform `lua_file_path` through the build cache, the bytecode is returned along with the code if
+/// `load_bytecode` is set, so that the loader does not look up the entry again.
+fn transform_lua_entry(lua_file_path: &str, load_bytecode: bool) -> Transformed {
+    #[cfg(feature = "print-time")]
+    let start = Instant::now();
+
+    let content = std::fs::read_to_string(lua_file_path).unwrap_or_else(|e| {
+        error::raise(
+            TransformStage::Read,
+            None,
+            format!("Failed to read file => {}", e),
+        )
+    });
+
+    let pipeline = legacy_pipeline();
+    let settings = pipeline.resolve(&content, lua_file_path);
+    let config = settings.config.clone();
+    let no_cache = settings.directives.no_cache == Some(true) || *ENV_NO_CACHE || *ENV_GEN_ONLY;
+
+    let build_cache_dir = cache_dir_for(config.as_deref(), lua_file_path);
+    let cached_file = cache::entry_path(&build_cache_dir, lua_file_path);
+    let backend = cache_backend_for(config.as_deref());
+    let backend = backend.as_ref();
+    let bytecode = bytecode_strip(config.as_deref());
 
     #[cfg(feature = "debug")]
-    log::debug!(
-        "{debug_prefix} first_line: \"{}\"",
-        first_line.strip_suffix("\n").unwrap_or_default()
-    );
+    let debug_prefix = format!("[transform_lua] <{lua_file_path}>");
 
     #[cfg(feature = "debug")]
     log::debug!(
@@ -249,146 +582,142 @@ pub fn transform_lua(file_path: *const c_char) -> *const c_char {
         ENV_GEN_ONLY.clone()
     );
 
-    if !std::fs::exists(&*BUILD_CACHE_DIR).unwrap_or(false) {
-        std::fs::create_dir_all(&*BUILD_CACHE_DIR).expect("Failed to create directory");
-
-        #[cfg(feature = "debug")]
-        log::debug!(
-            "{debug_prefix} create cache dir at {}",
-            BUILD_CACHE_DIR.clone()
-        );
-    } else {
-        if !no_cache {
-            if std::fs::exists(&cached_file).unwrap_or(false) {
-                #[cfg(feature = "debug")]
-                log::debug!("{debug_prefix} use cache file");
-
-                if get_mtime(&lua_file_path) <= get_mtime(&cached_file) {
-                    let cached_first_line = {
-                        let file = File::open(&cached_file)
-                            .expect(&format!("Failed to open file => {}", cached_file));
-                        let mut reader = std::io::BufReader::new(file);
-                        let mut first_line = String::new();
-                        reader.read_line(&mut first_line).unwrap();
-
-                        first_line
-                    };
-
-                    let (param_table, need_rebuild) = parse_param_table(&cached_first_line);
-
-                    #[cfg(feature = "debug")]
-                    log::debug!("{debug_prefix} cache_first_line: {} need_rebuild: {need_rebuild} parm_table: {param_table:?}", cached_first_line.strip_suffix("\n").unwrap_or_default());
-
-                    if !need_rebuild {
-                        let code = std::fs::read_to_string(&cached_file).unwrap();
-                        let c_str = CString::new(code).unwrap();
-
-                        #[cfg(feature = "print-time")]
-                        {
-                            let duration = start.elapsed();
-                            println!(
-                                "[luajit_pro_helper] Time elapsed(cached) in transform_lua() is: {:?}, file: {}",
-                                duration, lua_file_path
-                            );
-                            std::io::stdout().flush().unwrap();
-                        }
-
-                        return c_str.into_raw();
-                    }
-                }
-            }
-        }
-    }
-
-    let (param_table, _) = parse_param_table(&first_line);
+    let param_table = settings.param_table();
 
     #[cfg(feature = "debug")]
     log::debug!("{debug_prefix} parm_table: {param_table:?}");
 
-    let lockfile_path = format!("{}.lock", cached_file);
-    let mut lockfile = match LockFile::open(lockfile_path.as_str()) {
-        Ok(f) => f,
-        Err(e) => {
-            panic!(
-                "Failed to open lock file, path: {}, error: {}",
-                lockfile_path, e
-            );
+    error::set_stage(TransformStage::Cache);
+    let cache_key = cache_key(&pipeline, lua_file_path, &content, &settings, bytecode);
+
+    if !no_cache {
+        if let Some(transformed) = load_cached(
+            backend,
+            &cached_file,
+            &cache_key,
+            lua_file_path,
+            bytecode,
+            load_bytecode,
+        ) {
+            #[cfg(feature = "debug")]
+            log::debug!("{debug_prefix} use cache file {cached_file}, key: {cache_key}");
+
+            #[cfg(feature = "print-time")]
+            {
+                let duration = start.elapsed();
+                println!(
+                    "[luajit_pro_helper] Time elapsed(cached) in transform_lua() is: {:?}, file: {}",
+                    duration, lua_file_path
+                );
+                std::io::stdout().flush().unwrap();
+            }
+
+            return transformed;
         }
-    };
-    if let Err(e) = lockfile.lock() {
-        panic!(
-            "[acquire_lock] Failed to lock, path: {}, err: {}",
-            lockfile_path, e
-        );
     }
 
-    let content = std::fs::read_to_string(lua_file_path).unwrap();
-    let new_content = transform_lua_code(&content, lua_file_path, param_table.clone());
-
-    let new_content = if let Some(first_newline_pos) = new_content.find('\n') {
-        let old_first_line = first_line.clone();
-        let start = first_line.find("{");
-        let end = first_line.rfind('}');
-        let mut result = if let (Some(start), Some(end)) = (start, end) {
-            let before = &old_first_line[..start];
-            let after = &old_first_line[end + 1..];
-            let serialized_param = serialize_param_table(param_table);
-
-            #[cfg(feature = "debug")]
-            log::debug!("{debug_prefix} before: <{before}> serialized_param: <{serialized_param}> after: <{after}>");
+    let lock = backend.lock(&cached_file).unwrap_or_else(|e| {
+        error::raise(
+            TransformStage::Cache,
+            None,
+            format!("Failed to lock the cache entry => {cached_file}.lock, {e}"),
+        )
+    });
+
+    // Another process may have generated the entry while we were waiting for the lock
+    if lock.is_some() && !no_cache {
+        if let Some(transformed) = load_cached(
+            backend,
+            &cached_file,
+            &cache_key,
+            lua_file_path,
+            bytecode,
+            load_bytecode,
+        ) {
+            return transformed;
+        }
+    }
+    if !no_cache {
+        cache::record_miss();
+    }
 
-            let ret = format!("{}{}{}", before, serialized_param, after)
-                .strip_suffix("\n")
-                .unwrap_or_default()
-                .to_string();
+    let ((new_content, source_map), tracked) =
+        deps::track(|| transform_lua_code_with_map(&content, lua_file_path, param_table.clone()));
 
-            #[cfg(feature = "debug")]
-            log::debug!("{debug_prefix} result_new_line: <{ret}>");
+    #[cfg(feature = "debug")]
+    log::debug!("{debug_prefix} dependencies: {tracked:?}");
+
+    let new_content = match directives::header_end(&content) {
+        // Files transformed because of the project config have no header to rewrite
+        None => new_content,
+        Some(header_end) => {
+            let header = rewrite_header(
+                &content[directives::header_start(&content)..header_end],
+                &param_table,
+            );
 
-            ret
-        } else {
             #[cfg(feature = "debug")]
-            log::debug!("{debug_prefix} Could not find start or end of first line, use original first line: <{}>", first_line);
-
-            first_line
-        };
-
-        let new_first_line = &new_content[..first_newline_pos];
-
-        #[cfg(feature = "debug")]
-        log::debug!(
-            "{debug_prefix} old_first_line: <{}> new_first_line: <{new_first_line}> result_first_line: <{result}>",
-            old_first_line.strip_suffix("\n").unwrap_or_default()
-        );
-
-        if new_first_line.contains("luajit-pro") {
-            result.push_str(&new_content[first_newline_pos..]);
-        } else {
-            if old_first_line.contains("luajit-pro") {
-                if new_first_line.contains("tl_compat") || new_first_line.contains("bit") {
-                    result = result.strip_suffix("\n").unwrap_or_default().to_string() + " ";
-                    result.push_str(&new_content);
-                } else {
-                    result = format!("{result} {}", &new_content);
+            log::debug!("{debug_prefix} header: <{header}>");
+
+            // The shebang line(if any) stays in front of the header
+            let new_header_start = directives::header_start(&new_content);
+            let (shebang, rest) = new_content.split_at(new_header_start);
+            match directives::header_end(&new_content) {
+                // The header comment is still there, the rewritten one has the same number of lines
+                Some(new_header_end) => {
+                    format!("{shebang}{header}{}", &new_content[new_header_end..])
                 }
-            } else {
-                result.push_str(&new_content);
+                // Removed along with the other comments, put it in front of the first line
+                None => format!("{shebang}{} {rest}", directives::fold_header(&header)),
             }
         }
-        result
-    } else {
-        #[cfg(feature = "debug")]
-        log::debug!("{debug_prefix} No newline found!");
-
-        new_content
     };
 
     #[cfg(feature = "debug")]
     log::trace!("{debug_prefix} new_content:\n----------\n{new_content}\n----------\n");
 
-    std::fs::write(&cached_file, &new_content).expect("Failed to write to file");
+    // Written before the metadata so that a valid entry always has its bytecode
+    let bytecode = bytecode
+        .filter(|_| load_bytecode || !backend.read_only())
+        .map(|strip| store_bytecode(backend, &cached_file, lua_file_path, &new_content, strip));
+
+    // A read-only backend(e.g. a bundle) keeps serving its entries, the new code is not stored
+    if !backend.read_only() {
+        error::set_stage(TransformStage::Cache);
+        cache::store(
+            backend,
+            &cached_file,
+            lua_file_path,
+            &cache_key,
+            &tracked,
+            &new_content,
+        )
+        .unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Cache,
+                None,
+                format!("Failed to write the cache entry => {cached_file}, {e}"),
+            )
+        });
+
+        // The header rewrite above keeps the number of lines, so the map still lines up with the cached file
+        backend
+            .write(
+                &format!("{}.map", cached_file),
+                source_map.to_json().as_bytes(),
+            )
+            .unwrap_or_else(|e| {
+                error::raise(
+                    TransformStage::Cache,
+                    None,
+                    format!("Failed to write source map => {cached_file}.map, {e}"),
+                )
+            });
+    }
+
+    drop(lock);
 
-    let c_str = CString::new(if *ENV_GEN_ONLY {
+    let ret = if *ENV_GEN_ONLY {
         println!(
             "[luajit_pro_helper] [gen only] Output file: {}",
             cached_file
@@ -396,8 +725,7 @@ pub fn transform_lua(file_path: *const c_char) -> *const c_char {
         "".to_owned()
     } else {
         new_content
-    })
-    .unwrap();
+    };
 
     #[cfg(feature = "print-time")]
     {
@@ -409,9 +737,8 @@ pub fn transform_lua(file_path: *const c_char) -> *const c_char {
         std::io::stdout().flush().unwrap();
     }
 
-    if let Err(e) = lockfile.unlock() {
-        panic!("Failed to unlock, path: {}, err: {}", lockfile_path, e);
+    Transformed {
+        code: ret,
+        bytecode: bytecode.filter(|_| load_bytecode),
     }
-
-    c_str.into_raw()
 }
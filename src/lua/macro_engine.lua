@@ -121,6 +121,87 @@ _G._check_keep_line = function()
 	return kl
 end
 
+-- Files read by the comp-time code, collected by the Rust side to invalidate the cache once they change
+local deps = {}
+local function add_dep(filename)
+	if type(filename) == "string" then
+		deps[#deps + 1] = filename
+	end
+end
+
+local old_io_open = io.open
+io.open = function(filename, mode, ...)
+	local file, err = old_io_open(filename, mode, ...)
+	if file and (mode == nil or mode:find("r")) then
+		add_dep(filename)
+	end
+	return file, err
+end
+
+local old_dofile = dofile
+dofile = function(filename, ...)
+	add_dep(filename)
+	return old_dofile(filename, ...)
+end
+
+local old_loadfile = loadfile
+loadfile = function(filename, ...)
+	add_dep(filename)
+	return old_loadfile(filename, ...)
+end
+
+-- Lua modules loaded by the comp-time code and their files, unloaded by `_unload_modules` once a file changes
+local required = {}
+
+local old_require = require
+require = function(module_name, ...)
+	-- Modules stay in `package.loaded`, record them every time so that all dependent files know about them
+	local filename = package.searchpath(module_name, package.path)
+	add_dep(filename)
+	if filename and package.loaded[module_name] == nil then
+		required[module_name] = filename
+	end
+	return old_require(module_name, ...)
+end
+
+-- Env vars read by the comp-time code(including `env_vars`), `false` marks an unset one
+local env_deps = {}
+local old_getenv = os.getenv
+os.getenv = function(key)
+	local value = old_getenv(key)
+	if type(key) == "string" then
+		env_deps[key] = value or false
+	end
+	return value
+end
+
+_G._take_deps = function()
+	local ret = deps
+	deps = {}
+	return ret
+end
+
+_G._required_files = function()
+	local ret = {}
+	for _, filename in pairs(required) do
+		ret[#ret + 1] = filename
+	end
+	return ret
+end
+
+_G._unload_modules = function()
+	for module_name in pairs(required) do
+		package.loaded[module_name] = nil
+	end
+	required = {}
+end
+
+_G._take_env_deps = function()
+	local ret = env_deps
+	env_deps = {}
+	return ret
+end
+
 _G.render = function(str)
 	-- Get upvalues from the caller
 	local level = 2
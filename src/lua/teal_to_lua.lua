@@ -102,9 +102,15 @@ local function filename_to_module_name(filename)
 	return (filename:gsub("%.lua$", ""):gsub("%.d%.tl$", ""):gsub("%.tl$", ""):gsub("[/\\]", "."))
 end
 
-local function process_module(filename, env)
+local function process_module(filename, env, input)
 	local module_name = filename_to_module_name(filename)
-	local result, err = tl.process(filename, env)
+	local result, err
+	if input then
+		-- In-memory chunks(e.g. from `load`/`loadstring`) have no file to read from
+		result, err = tl.process_string(input, false, env, filename)
+	else
+		result, err = tl.process(filename, env)
+	end
 	if result then
 		env.modules[module_name] = result.type
 	end
@@ -131,6 +137,7 @@ local function filter_warnings(tlconfig, result)
 end
 
 local report_all_errors
+local first_syntax_error
 do
 	local function report_errors(category, errors)
 		if not errors then
@@ -157,6 +164,10 @@ do
 
 			local syntax_err = report_errors("syntax error", result.syntax_errors)
 			if syntax_err then
+				if not any_syntax_err then
+					local err = result.syntax_errors[1]
+					first_syntax_error = err.filename .. ":" .. err.y .. ":" .. err.x .. ": " .. (err.msg or "")
+				end
 				any_syntax_err = true
 			elseif not syntax_only then
 				filter_warnings(tlconfig, result)
@@ -169,7 +180,7 @@ do
 	end
 end
 
-local function teal_to_lua(input_file_name, syntax_only)
+local function teal_to_lua(input_file_name, syntax_only, input)
 	turbo(true)
 	local tlconfig = {
 		include_dir = {},
@@ -198,7 +209,7 @@ local function teal_to_lua(input_file_name, syntax_only)
 		output_file = input_file_name .. ".lua",
 	}
 
-	res.tl_result, err = process_module(input_file_name, env)
+	res.tl_result, err = process_module(input_file_name, env, input)
 
 	if err then
 		die(err)
@@ -209,11 +220,24 @@ local function teal_to_lua(input_file_name, syntax_only)
 		ret_code = tl.generate(res.tl_result.ast, tlconfig.gen_target, pp_opts)
 	end
 
+	first_syntax_error = nil
 	local _, any_syntax_err, _, _ = report_all_errors(tlconfig, env, syntax_only or false)
-	assert(not any_syntax_err, "Failed to generate Lua code(syntax error found!)")
+	if any_syntax_err then
+		-- Leave the turbo mode before raising the error, otherwise the next call will run with GC stopped
+		turbo(false)
+		error("Failed to generate Lua code(syntax error found!) " .. tostring(first_syntax_error), 0)
+	end
+
+	-- Other modules loaded while type checking, they are dependencies of the generated code
+	local loaded_files = {}
+	for _, name in ipairs(env.loaded_order) do
+		if name ~= input_file_name then
+			loaded_files[#loaded_files + 1] = name
+		end
+	end
 
 	turbo(false)
-	return ret_code
+	return ret_code, loaded_files
 end
 
 _G.teal_to_lua = teal_to_lua
@@ -10,14 +10,31 @@ use full_moon::{
 };
 
 use crate::ast_utilis;
+use crate::error::{self, Report};
+
+/// Optimizer passes, each one handles the `--[[@<pass>]]` annotation of the same name.
+pub const OPTIMIZER_PASSES: &[&str] = &["comp_time_enum", "used"];
 
 pub struct LuaOptimizer {
     pub enum_map: Option<HashMap<String, HashMap<String, String>>>,
+    /// Enabled passes, all of them if `None`
+    pub passes: Option<Vec<String>>,
 }
 
 impl LuaOptimizer {
     pub fn new() -> LuaOptimizer {
-        LuaOptimizer { enum_map: None }
+        LuaOptimizer {
+            enum_map: None,
+            passes: None,
+        }
+    }
+
+    fn pass_enabled(&self, annotation_name: &str) -> bool {
+        let pass = annotation_name.trim_start_matches('@');
+        match &self.passes {
+            Some(passes) => passes.iter().any(|p| p == pass),
+            None => true,
+        }
     }
 }
 
@@ -43,7 +60,7 @@ impl VisitorMut for LuaOptimizer {
                     };
                 });
 
-                if has_annotation {
+                if has_annotation && self.pass_enabled(&annotation_name) {
                     let name_vec: Vec<String> = local_assignment
                         .names()
                         .pairs()
@@ -53,10 +70,13 @@ impl VisitorMut for LuaOptimizer {
                         })
                         .collect();
                     if name_vec.len() == 1 {
-                        assert!(
-                            local_assignment.expressions().pairs().count() == 1,
-                            "[{annotation_name}] Only support one expression!"
-                        );
+                        if local_assignment.expressions().pairs().count() != 1 {
+                            Report::new(format!("`{annotation_name}` expects exactly one value"))
+                                .code(error::E_ANNOTATION)
+                                .at_token(local_tk)
+                                .hint(format!("e.g. `local --[[{annotation_name}]] name = value`"))
+                                .raise()
+                        }
 
                         match annotation_name.as_str() {
                             "@comp_time_enum" => {
@@ -99,7 +119,11 @@ impl VisitorMut for LuaOptimizer {
                                                                 Some(str.token().to_string())
                                                             }
                                                             _ => {
-                                                                println!("[luajit_pro_helper] [@comp_time_enum] Unexpected Expression: <{}>, enum_name: {}", value.to_string(), enum_name);
+                                                                Report::new(format!("`{}.{}` is not inlined, its value `{}` is not a literal", enum_name, key.token(), value.to_string().trim()))
+                                                                    .code(error::W_COMP_TIME_ENUM)
+                                                                    .at_token(key)
+                                                                    .hint("Only number and string values of a `@comp_time_enum` are inlined")
+                                                                    .warn();
                                                                 None
                                                             }
                                                         };
@@ -118,7 +142,11 @@ impl VisitorMut for LuaOptimizer {
                                                     _ => {}
                                                 });
                                         }
-                                        _ => panic!("[@comp_time_enum] Unexpected Expression: {:?}", expr),
+                                        _ => Report::new("`@comp_time_enum` expects a table constructor")
+                                            .code(error::E_ANNOTATION)
+                                            .at_token(local_tk)
+                                            .hint("e.g. `local --[[@comp_time_enum]] Color = { RED = 1, GREEN = 2 }`")
+                                            .raise(),
                                     }
                                 });
                                 if self.enum_map.is_none() {
@@ -175,7 +203,14 @@ impl VisitorMut for LuaOptimizer {
                             _ => panic!("Unknown annotation: {}", annotation_name),
                         }
                     } else {
-                        panic!("[{annotation_name}] Should only have one variable name!");
+                        Report::new(format!(
+                            "`{annotation_name}` applies to a single variable, got {}",
+                            name_vec.len()
+                        ))
+                        .code(error::E_ANNOTATION)
+                        .at_token(local_tk)
+                        .hint("Split the declaration into one `local` per variable")
+                        .raise()
                     }
                 } else {
                     node
@@ -1,14 +1,20 @@
 use full_moon::{
     ast::{
-        span::ContainedSpan, Call, FunctionArgs, FunctionCall, FunctionDeclaration, Index,
-        LastStmt, Parameter, Prefix, Return, Suffix,
+        punctuated::Punctuated, span::ContainedSpan, Block, Call, FunctionArgs, FunctionCall,
+        FunctionDeclaration, FunctionName, Index, LastStmt, Parameter, Prefix, Return, Suffix,
     },
+    node::Node,
     tokenizer::{Token, TokenReference, TokenType},
     visitors::VisitorMut,
     ShortString,
 };
 
-use crate::{ast_utilis, lang_utils, transform_lua_code};
+use crate::deps;
+use crate::directives::{self, ParamValue};
+use crate::error::{self, Report, TransformStage};
+use crate::pipeline::Pipeline;
+use crate::source_map::{IncludedSource, SourceMap};
+use crate::{ast_utilis, lang_utils};
 
 trait StringLuaCommentRemove {
     fn remove_lua_comments(&self) -> String;
@@ -22,7 +28,13 @@ impl StringLuaCommentRemove for String {
 
 pub struct LuaTransformer {
     pub file_path: Option<String>,
-    pub input_param_list: Option<Vec<(String, String)>>,
+    pub input_param_list: Option<Vec<(String, ParamValue)>>,
+    /// Transformed `__LJP:Include` files, used to map the inlined code back to them
+    pub included_sources: Vec<IncludedSource>,
+    /// Keep every original statement on its original line(the `preserve-lines` directive)
+    pub preserve_lines: bool,
+    /// Transforms the `__LJP:Include` files which have the luajit-pro header
+    pub pipeline: Pipeline,
 }
 
 struct LuaLastReturnRemover;
@@ -51,67 +63,150 @@ impl LuaTransformer {
         LuaTransformer {
             file_path: None,
             input_param_list: None,
+            included_sources: Vec::new(),
+            preserve_lines: false,
+            pipeline: Pipeline::default(),
         }
     }
 }
 
-impl LuaTransformer {
-    #[inline]
-    fn load_param_list_into_lua_env(&self) {
-        if let Some(input_param_list) = &self.input_param_list {
-            let mut code = String::new();
-            for (key, value) in input_param_list {
-                if value == "true" || value == "1" {
-                    code = code + "rawset(_G, \"" + key + "\", true)\n";
-                } else if value == "false" || value == "0" {
-                    code = code + "rawset(_G, \"" + key + "\", false)\n";
-                } else {
-                    panic!(
-                        "[load_param_list_into_env] invalid value: {}, key: {}",
-                        value, key
-                    )
+/// Fit `code` into exactly `line_count` lines, shorter code is padded with blank lines and
+/// the trailing lines of longer code are joined into the last line.
+///
+/// Fails if a line break to join is part of a token(a multi-line string or comment) or ends a `--` comment,
+/// joining it would change the string or comment out the code after it.
+fn fit_into_lines(code: &str, line_count: usize) -> Result<String, String> {
+    let line_count = line_count.max(1);
+    let mut lines: Vec<String> = code.lines().map(|l| l.to_string()).collect();
+    if lines.len() > line_count {
+        // The breaks after the lines `line_count..lines.len()`(1-based) are joined. Code which does not
+        // parse is joined anyway, parsing the generated code reports the error.
+        if let Ok(ast) = full_moon::parse(code) {
+            let joined = line_count..lines.len();
+            for token_ref in ast.tokens() {
+                let trivia = token_ref
+                    .leading_trivia()
+                    .chain(token_ref.trailing_trivia());
+                for token in trivia.chain(std::iter::once(token_ref.token())) {
+                    let (start, end) = (token.start_position().line(), token.end_position().line());
+                    let mut breaks = match token.token_type() {
+                        TokenType::Whitespace { .. } => continue,
+                        TokenType::SingleLineComment { .. } => start..start + 1,
+                        _ => start..end,
+                    };
+                    if let Some(line) = breaks.find(|line| joined.contains(line)) {
+                        return Err(format!(
+                            "Cannot fit {} lines of generated code into {line_count}, line {line} would be joined with the next one, breaking `{}`",
+                            lines.len(),
+                            token.to_string().lines().next().unwrap_or("").trim()
+                        ));
+                    }
                 }
             }
-            code = code + "return \"\"";
-            lang_utils::lua_dostring("[load_param_list_into_lua_env]", &code);
         }
+        let tail = lines.split_off(line_count - 1).join(" ");
+        lines.push(tail);
     }
 
-    #[inline]
-    fn unload_param_list_from_lua_env(&self) {
-        if let Some(input_param_list) = &self.input_param_list {
-            let mut code = String::new();
-            for (key, _) in input_param_list {
-                code = code + "rawset(_G, \"" + key + "\", nil)\n";
-            }
-            code = code + "return \"\"";
-            lang_utils::lua_dostring("[unload_param_list_from_lua_env]", &code);
-        }
+    let mut ret = lines.join("\n");
+    for _ in lines.len().max(1)..line_count {
+        ret.push('\n');
+    }
+    Ok(ret)
+}
+
+impl LuaTransformer {
+    /// Replace the whole comp-time block by `code` squeezed into the lines the block occupied.
+    fn replace_comp_time_in_place(
+        &self,
+        node: FunctionDeclaration,
+        code: &str,
+    ) -> FunctionDeclaration {
+        let start_line = node.function_token().token().start_position().line();
+        let end_line = node.body().end_token().token().end_position().line();
+        let code = fit_into_lines(code, end_line - start_line + 1).unwrap_or_else(|e| {
+            Report::new(e)
+                .stage(TransformStage::CompTime)
+                .code(error::E_COMP_TIME_LINES)
+                .at_token(node.function_token())
+                .hint("With `preserve-lines` the generated code must fit into the lines of the `__LJP:COMP_TIME` block, add blank lines to the block or keep the strings and comments it generates on one line")
+                .raise()
+        });
+
+        let function_token = TokenReference::new(
+            node.function_token().leading_trivia().cloned().collect(),
+            Token::new(TokenType::Whitespace {
+                characters: ShortString::new(&code),
+            }),
+            vec![],
+        );
+        let end_token = TokenReference::new(
+            vec![],
+            Token::new(TokenType::Whitespace {
+                characters: ShortString::new(""),
+            }),
+            node.body().end_token().trailing_trivia().cloned().collect(),
+        );
+        let body = node
+            .body()
+            .clone()
+            .with_parameters_parentheses(ContainedSpan::new(
+                ast_utilis::create_empty_token_ref(),
+                ast_utilis::create_empty_token_ref(),
+            ))
+            .with_parameters(Punctuated::new())
+            .with_type_specifiers(Vec::new())
+            .with_return_type(None)
+            .with_block(Block::new())
+            .with_end_token(end_token);
+
+        node.with_function_token(function_token)
+            .with_name(
+                FunctionName::new(ast_utilis::create_punc(
+                    &ast_utilis::create_empty_token_ref(),
+                ))
+                .with_method(None),
+            )
+            .with_body(body)
     }
 
     fn resolve_comp_time(&self, node: FunctionDeclaration) -> FunctionDeclaration {
+        const PARAMS_HINT: &str = "`__LJP:COMP_TIME` accepts at most one parameter";
+
         // Remove parameters
-        let mut parameter_vec: Vec<String> = Vec::new();
-        let mut old_parameter_name_token: Option<TokenReference> = None;
+        let mut parameter_vec: Vec<TokenReference> = Vec::new();
         node.body().parameters().pairs().for_each(|param| {
             let param = param.value();
             match param.clone() {
                 Parameter::Ellipsis(ellipsis) => {
-                    panic!("Unexpected Ellipsis {}", ellipsis);
+                    Report::new("`...` is not allowed in the parameters of `__LJP:COMP_TIME`")
+                        .code(error::E_COMP_TIME_VARARG)
+                        .at_token(&ellipsis)
+                        .hint(PARAMS_HINT)
+                        .raise()
                 }
-                Parameter::Name(name) => {
-                    old_parameter_name_token = Some(name.clone());
-                    parameter_vec.push(name.to_string());
-                }
-                _ => panic!("{:?}", param),
+                Parameter::Name(name) => parameter_vec.push(name),
+                _ => Report::new(format!(
+                    "Unsupported parameter `{}`",
+                    param.to_string().trim()
+                ))
+                .code(error::E_COMP_TIME_PARAMS)
+                .at_token(node.body().parameters_parentheses().tokens().0)
+                .hint(PARAMS_HINT)
+                .raise(),
             }
         });
-        assert!(
-            parameter_vec.len() <= 1,
-            "More than 1 parameters, got {} => \"{}\"",
-            parameter_vec.len(),
-            parameter_vec.join(", ")
-        );
+        if parameter_vec.len() > 1 {
+            Report::new(format!(
+                "`__LJP:COMP_TIME` has {} parameters",
+                parameter_vec.len()
+            ))
+            .code(error::E_COMP_TIME_PARAMS)
+            .at_token(&parameter_vec[1])
+            .hint(PARAMS_HINT)
+            .raise()
+        }
+        let old_parameter_name_token = parameter_vec.pop();
 
         let new_func_body = node
             .body()
@@ -133,25 +228,53 @@ impl LuaTransformer {
             .to_string();
 
         let comp_time_ret = {
-            // Make parameter list available to Lua at the compile time context.
-            self.load_param_list_into_lua_env();
-
-            let (mut ret, keep_line) = lang_utils::lua_dostring(
-                &(self.file_path.clone().unwrap_or_default() + " " + parameter_name.as_str()),
+            // Make parameter list available to Lua at the compile time context, the previous values of
+            // these globals are restored when `_params` is dropped, even if the comp-time code fails.
+            let _params = self
+                .input_param_list
+                .as_deref()
+                .map(lang_utils::set_comp_time_params);
+
+            let file_path = self.file_path.clone().unwrap_or_default();
+            // The block starts right after the trailing trivia(up to the line break) of `)`
+            let parentheses_end = node.body().parameters_parentheses().tokens().1;
+            let block_line = parentheses_end.token().end_position().line()
+                + parentheses_end
+                    .trailing_trivia()
+                    .map(|trivia| trivia.to_string().matches('\n').count())
+                    .sum::<usize>();
+            let (mut ret, keep_line) = lang_utils::try_lua_dostring_at(
+                &(file_path.clone() + " " + parameter_name.as_str()),
+                &file_path,
+                block_line,
                 node.body().block().to_string().as_str(),
-            );
+            )
+            .unwrap_or_else(|e| {
+                let report = Report::new(e.as_str())
+                    .stage(TransformStage::CompTime)
+                    .code(error::E_COMP_TIME_RUNTIME);
+                match lang_utils::comp_time_error_line(&file_path, &e) {
+                    Some(line) => report.at(Some(line), None),
+                    None => report
+                        .at_token(node.function_token())
+                        .hint("The error was raised by the `__LJP:COMP_TIME` block starting here"),
+                }
+                .raise()
+            });
 
-            if keep_line {
+            if keep_line || self.preserve_lines {
                 ret = ret.remove_lua_comments();
             } else {
                 ret = ret.remove_lua_comments().replace("\n", " ");
             }
 
-            self.unload_param_list_from_lua_env();
-
             ret
         };
 
+        if self.preserve_lines {
+            return self.replace_comp_time_in_place(node, &comp_time_ret);
+        }
+
         let ret = node
             .clone()
             .with_function_token(ast_utilis::insert_before_token(
@@ -175,7 +298,11 @@ impl VisitorMut for LuaTransformer {
                 if node.name().to_string().contains("__LJP:COMP_TIME")
                     || node.name().to_string().contains("_G.__LJP:COMP_TIME")
                 {
-                    panic!("Function name for the `__LJP:COMP_TIME` should be the same line as the `function` token.")
+                    Report::new("Unexpected `__LJP:COMP_TIME` function name")
+                        .code(error::E_COMP_TIME_NAME)
+                        .at_token(node.function_token())
+                        .hint("Write `function __LJP:COMP_TIME()` on a single line, without spaces or comments in the name")
+                        .raise()
                 }
                 node
             }
@@ -201,17 +328,10 @@ impl VisitorMut for LuaTransformer {
         if func_name.starts_with("__LJP") {
             (full_func_name, func_arg) = ast_utilis::get_func_call_name(&node)
         } else if func_name.starts_with("_G") {
-            if suffix_vec.len() > 0 {
-                match suffix_vec[0].clone() {
-                    Suffix::Index(index) => match index {
-                        Index::Dot { dot: _, name } => {
-                            if name.token().to_string().to_uppercase() == "__LJP" {
-                                (full_func_name, func_arg) = ast_utilis::get_func_call_name(&node);
-                            }
-                        }
-                        _ => panic!(),
-                    },
-                    _ => panic!(),
+            // Anything but `_G.__LJP...`(e.g. `_G["x"]()` or `_Gx()`) is a plain call
+            if let Some(Suffix::Index(Index::Dot { dot: _, name })) = suffix_vec.first() {
+                if name.token().to_string().to_uppercase() == "__LJP" {
+                    (full_func_name, func_arg) = ast_utilis::get_func_call_name(&node);
                 }
             }
         };
@@ -225,37 +345,74 @@ impl VisitorMut for LuaTransformer {
                 | "__LJP:INCLUDE_NO_RETURN"
                 | "_G.__LJP:INCLUDE_NO_RETURN"
         ) {
+            let include_line = node.prefix().start_position().map(|p| p.line());
             let new_prefix = {
-                let (include_file, _) = lang_utils::lua_dostring(
+                error::set_stage(TransformStage::Include);
+                let include_error = |message: String| {
+                    let report = Report::new(message).code(error::E_INCLUDE);
+                    match node.prefix() {
+                        Prefix::Name(token) => report.at_token(token),
+                        _ => report,
+                    }
+                    .hint("The included module is looked up in `package.path`")
+                };
+                let (include_file, _) = lang_utils::try_lua_dostring(
                     "__LJP:INCLUDE",
                     &format!(
                         "return assert(package.searchpath({}, package.path))",
                         func_arg
                     ),
-                );
+                )
+                .unwrap_or_else(|e| {
+                    include_error(format!("Cannot find {func_arg} => {e}")).raise()
+                });
+                deps::record(&include_file);
                 let mut include_code = std::fs::read_to_string(include_file.clone())
-                    .expect(format!("Failed to read file => {}", include_file).as_str());
-                if let Some(first_line) = include_code.lines().next() {
-                    if first_line.contains("luajit-pro") {
-                        // Recursively transform the included code
-                        include_code = transform_lua_code(&include_code, &include_file, None);
-                    }
+                    .unwrap_or_else(|e| {
+                        include_error(format!("Failed to read file => {}, {}", include_file, e))
+                            .raise()
+                    });
+                let mut include_map = None;
+                if directives::starts_with_header(&include_code) {
+                    // Recursively transform the included code
+                    let (code, map) = self.pipeline.run(&include_code, &include_file);
+                    include_code = code;
+                    include_map = Some(map);
                 }
+                self.included_sources.push(IncludedSource::new(
+                    &include_code,
+                    include_map
+                        .unwrap_or_else(|| SourceMap::identity(&include_file, &include_code)),
+                ));
 
                 if full_func_name.to_uppercase().contains("NO_RETURN") {
-                    let ast = full_moon::parse(&include_code).unwrap();
+                    let ast = ast_utilis::parse_lua(&include_code);
                     let mut return_remover = LuaLastReturnRemover::new();
                     include_code = return_remover.visit_ast(ast).to_string();
                 }
 
-                include_code = include_code.remove_lua_comments().replace("\n", " ");
+                // The included code takes the line of the call, the call itself is commented out
+                include_code = if self.preserve_lines {
+                    fit_into_lines(&include_code.remove_lua_comments(), 1).unwrap_or_else(|e| {
+                        include_error(e)
+                            .hint("With `preserve-lines` the included code must fit into the line of `__LJP:INCLUDE`, keep its strings on one line")
+                            .raise()
+                    })
+                } else {
+                    include_code.remove_lua_comments().replace("\n", " ")
+                };
+                error::set_stage(TransformStage::CompTime);
 
                 match node.prefix() {
                     Prefix::Name(token) => Prefix::Name(ast_utilis::insert_before_token(
                         &ast_utilis::insert_before_token(&token, include_code.as_str()),
                         " --[=====[ ",
                     )),
-                    _ => panic!("Unexpected Prefix {:?}", node.prefix()),
+                    prefix => error::raise(
+                        TransformStage::Include,
+                        include_line,
+                        format!("Unexpected `{prefix}` in `{}`", node.to_string().trim()),
+                    ),
                 }
             };
 
@@ -267,7 +424,11 @@ impl VisitorMut for LuaTransformer {
                             dot: dot,
                             name: name,
                         },
-                        _ => panic!("Unexpected Index {:?}", index),
+                        _ => error::raise(
+                            TransformStage::Include,
+                            include_line,
+                            format!("Unexpected `{index}` in `{}`", node.to_string().trim()),
+                        ),
                     }),
                     Suffix::Call(call) => Suffix::Call(match call {
                         Call::MethodCall(method_call) => {
@@ -288,16 +449,28 @@ impl VisitorMut for LuaTransformer {
                                 FunctionArgs::String(str) => FunctionArgs::String(
                                     ast_utilis::insert_after_token(&str, " --]=====]"),
                                 ),
-                                _ => panic!(
-                                    "Unexpected Call {}",
-                                    method_call.args().clone().to_string()
+                                args => error::raise(
+                                    TransformStage::Include,
+                                    include_line,
+                                    format!(
+                                        "Unexpected arguments `{args}` in `{}`",
+                                        node.to_string().trim()
+                                    ),
                                 ),
                             };
                             Call::MethodCall(method_call.with_args(new_args))
                         }
-                        _ => panic!("{:?}", call),
+                        _ => error::raise(
+                            TransformStage::Include,
+                            include_line,
+                            format!("Unexpected `{call}` in `{}`", node.to_string().trim()),
+                        ),
                     }),
-                    _ => panic!(),
+                    _ => error::raise(
+                        TransformStage::Include,
+                        include_line,
+                        format!("Unexpected `{suffix}` in `{}`", node.to_string().trim()),
+                    ),
                 })
                 .collect();
 
@@ -0,0 +1,531 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use full_moon::visitors::VisitorMut;
+
+use crate::cache_backend::{CacheBackend, FsBackend};
+use crate::config::{self, FormatConfig, ProjectConfig};
+use crate::directives::{self, Directives, Frontend, ParamValue};
+use crate::error::{self, Diagnostic, TransformError, TransformStage};
+use crate::lua_optimizer::{LuaOptimizer, OPTIMIZER_PASSES};
+use crate::lua_transformer::LuaTransformer;
+use crate::source_map::SourceMap;
+use crate::{ast_utilis, cache, deps, lang_utils};
+
+/// How a `Pipeline` uses its cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    /// Always transform, the cache is never read nor written
+    #[default]
+    Disabled,
+    /// Reuse the valid entries, store the newly generated ones
+    ReadWrite,
+    /// Always transform and overwrite the entry
+    Refresh,
+}
+
+/// Options of a `Pipeline`.
+///
+/// Precedence: options < project config(if enabled) < file header < env vars(if enabled).
+///
+/// ```no_run
+/// use luajit_pro_helper::{CachePolicy, Frontend, ParamValue, TransformOptions};
+///
+/// let pipeline = TransformOptions::new()
+///     .frontend(Frontend::Teal)
+///     .optimize(true)
+///     .param("LOG_LEVEL", ParamValue::Int(2))
+///     .cache(CachePolicy::ReadWrite, ".luajit_pro/build_cache")
+///     .build();
+/// let output = pipeline.transform("local a: integer = LOG_LEVEL\n", "main.tl").unwrap();
+/// println!("{}", output.code);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TransformOptions {
+    directives: Directives,
+    optimizer_passes: Option<Vec<String>>,
+    format_config: FormatConfig,
+    cache: CachePolicy,
+    cache_dir: Option<PathBuf>,
+    cache_backend: Option<Arc<dyn CacheBackend>>,
+    project_config: bool,
+    env_overrides: bool,
+    disable_optimizer: bool,
+}
+
+impl TransformOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn frontend(mut self, frontend: Frontend) -> Self {
+        self.directives.frontend = frontend;
+        self
+    }
+
+    /// Only check the Teal syntax, skip type checking
+    pub fn syntax_only(mut self, enable: bool) -> Self {
+        self.directives.syntax_only = Some(enable);
+        self
+    }
+
+    pub fn optimize(mut self, enable: bool) -> Self {
+        self.directives.opt = Some(enable);
+        self
+    }
+
+    /// Enabled optimizer passes(see `OPTIMIZER_PASSES`), all of them by default
+    pub fn optimizer_passes<I, S>(mut self, passes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.optimizer_passes = Some(passes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never run the optimizer, even for files with the `opt` directive(same as `LJP_NO_OPT=1`)
+    pub fn disable_optimizer(mut self, disable: bool) -> Self {
+        self.disable_optimizer = disable;
+        self
+    }
+
+    pub fn format(mut self, enable: bool) -> Self {
+        self.directives.format = Some(enable);
+        self
+    }
+
+    pub fn pretty(mut self, enable: bool) -> Self {
+        self.directives.pretty = Some(enable);
+        self
+    }
+
+    pub fn no_comment(mut self, enable: bool) -> Self {
+        self.directives.no_comment = Some(enable);
+        self
+    }
+
+    pub fn preserve_lines(mut self, enable: bool) -> Self {
+        self.directives.preserve_lines = Some(enable);
+        self
+    }
+
+    pub fn format_config(mut self, format_config: FormatConfig) -> Self {
+        self.format_config = format_config;
+        self
+    }
+
+    /// Add a param, the params of the file header override it
+    pub fn param(mut self, key: impl Into<String>, value: ParamValue) -> Self {
+        let key = key.into();
+        let params = self.directives.params.get_or_insert_with(Vec::new);
+        match params.iter_mut().find(|(k, _)| *k == key) {
+            Some(param) => param.1 = value,
+            None => params.push((key, value)),
+        }
+        self
+    }
+
+    pub fn cache(mut self, policy: CachePolicy, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache = policy;
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Where the cache files are stored, the files on disk(`FsBackend`) by default
+    pub fn cache_backend(mut self, backend: Arc<dyn CacheBackend>) -> Self {
+        self.cache_backend = Some(backend);
+        self
+    }
+
+    /// Read the defaults of the `ljp.toml` found above each file
+    pub fn project_config(mut self, enable: bool) -> Self {
+        self.project_config = enable;
+        self
+    }
+
+    /// Let env vars of the same name override the params
+    pub fn env_overrides(mut self, enable: bool) -> Self {
+        self.env_overrides = enable;
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline::new(self)
+    }
+}
+
+/// The result of `Pipeline::transform()`.
+#[derive(Debug, Clone)]
+pub struct TransformOutput {
+    pub code: String,
+    /// Mapping from `code` back to the sources, `None` if the code was loaded from the cache
+    pub source_map: Option<SourceMap>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Files the generated code depends on besides the source itself
+    pub deps: Vec<String>,
+    /// Env vars read at comp-time and their values(`None` if unset)
+    pub env: BTreeMap<String, Option<String>>,
+    pub from_cache: bool,
+}
+
+/// Everything the transformation of one file depends on besides its code.
+pub(crate) struct Settings {
+    pub(crate) directives: Directives,
+    pub(crate) params: Option<Vec<(String, ParamValue)>>,
+    pub(crate) format_config: FormatConfig,
+    pub(crate) optimizer_passes: Option<Vec<String>>,
+    /// The project config the settings were read from
+    pub(crate) config: Option<Arc<ProjectConfig>>,
+}
+
+impl Settings {
+    pub(crate) fn param_table(&self) -> Option<HashMap<&str, ParamValue>> {
+        self.params.as_ref().map(|params| {
+            params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.clone()))
+                .collect()
+        })
+    }
+}
+
+/// A configured transformer, all of its state lives in its options.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    options: TransformOptions,
+    /// Replaces the resolved params, used by the `transform_lua_code()` API
+    pub(crate) param_override: Option<Vec<(String, ParamValue)>>,
+}
+
+impl Pipeline {
+    pub fn new(options: TransformOptions) -> Self {
+        Pipeline {
+            options,
+            param_override: None,
+        }
+    }
+
+    pub fn options(&self) -> &TransformOptions {
+        &self.options
+    }
+
+    /// Transform `source`, `path` is used to resolve includes, the project config and the cache entry.
+    pub fn transform(&self, source: &str, path: &str) -> Result<TransformOutput, TransformError> {
+        error::catch(path, || {
+            let (mut output, diagnostics) =
+                error::collect_diagnostics(|| self.transform_cached(source, path));
+            output.diagnostics = diagnostics;
+            output
+        })
+    }
+
+    fn transform_cached(&self, source: &str, path: &str) -> TransformOutput {
+        let cache_dir = match (self.options.cache, &self.options.cache_dir) {
+            (CachePolicy::Disabled, _) | (_, None) => None,
+            (_, Some(cache_dir)) => Some(cache_dir.to_string_lossy().into_owned()),
+        };
+        let Some(cache_dir) = cache_dir else {
+            return self.transform_tracked(source, path);
+        };
+
+        let settings = self.resolve(source, path);
+        error::set_stage(TransformStage::Cache);
+        let backend = self
+            .options
+            .cache_backend
+            .clone()
+            .unwrap_or_else(|| Arc::new(FsBackend));
+        let backend = backend.as_ref();
+        let entry = cache::entry_path(&cache_dir, path);
+        let key = cache::compute_key(
+            path,
+            source,
+            &settings.param_table(),
+            &self.key_flags(&settings),
+        );
+
+        if self.options.cache == CachePolicy::ReadWrite {
+            if let (Some(code), Some(meta)) = (
+                cache::load(backend, &entry, &key),
+                cache::read_meta(backend, &entry),
+            ) {
+                return TransformOutput {
+                    code,
+                    source_map: None,
+                    diagnostics: Vec::new(),
+                    deps: meta.deps.into_iter().map(|dep| dep.path).collect(),
+                    env: meta.env,
+                    from_cache: true,
+                };
+            }
+            cache::record_miss();
+        }
+
+        if backend.read_only() {
+            return self.transform_tracked(source, path);
+        }
+        let _lock = backend.lock(&entry).unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Cache,
+                None,
+                format!("Failed to lock {entry}.lock => {e}"),
+            )
+        });
+
+        let output = self.transform_tracked(source, path);
+
+        error::set_stage(TransformStage::Cache);
+        let tracked = deps::Tracked {
+            files: output.deps.iter().cloned().collect(),
+            env: output.env.clone(),
+        };
+        cache::store(backend, &entry, path, &key, &tracked, &output.code).unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Cache,
+                None,
+                format!("Failed to write {entry} => {e}"),
+            )
+        });
+        output
+    }
+
+    fn transform_tracked(&self, source: &str, path: &str) -> TransformOutput {
+        let ((code, source_map), tracked) = deps::track(|| self.run(source, path));
+        let source = cache::canonical_path(path);
+        TransformOutput {
+            code,
+            source_map: Some(source_map),
+            diagnostics: Vec::new(),
+            deps: tracked
+                .files
+                .into_iter()
+                .filter(|dep| *dep != source)
+                .collect(),
+            env: tracked.env,
+            from_cache: false,
+        }
+    }
+
+    /// Flags of the cache key covering the resolved directives, format and optimizer settings,
+    /// shared with the loader entry points(see `cache_key()` of lib.rs).
+    pub(crate) fn key_flags(&self, settings: &Settings) -> Vec<(&'static str, String)> {
+        vec![
+            ("no-opt", self.options.disable_optimizer.to_string()),
+            (
+                "options",
+                format!(
+                    "{:?} {:?} {:?}",
+                    settings.directives, settings.format_config, settings.optimizer_passes
+                ),
+            ),
+        ]
+    }
+
+    pub(crate) fn resolve(&self, code: &str, path: &str) -> Settings {
+        let config = if self.options.project_config {
+            error::set_stage(TransformStage::Config);
+            config::load_for(path)
+        } else {
+            None
+        };
+
+        let mut directives = self.options.directives.clone();
+        if let Some(config) = &config {
+            directives.merge(
+                &config
+                    .directives_for(path)
+                    .unwrap_or_else(|e| error::raise(TransformStage::Config, None, e)),
+            );
+        }
+        error::set_stage(TransformStage::Header);
+        directives.merge(&Directives::from_code(code));
+        directives.check().unwrap_or_else(|e| {
+            error::raise(
+                TransformStage::Header,
+                Some(directives::header_line(code)),
+                e,
+            )
+        });
+
+        let params = match &self.param_override {
+            Some(params) => Some(params.clone()),
+            None if self.options.env_overrides => directives
+                .param_table()
+                .unwrap_or_else(|e| {
+                    error::raise(
+                        TransformStage::Header,
+                        Some(directives::header_line(code)),
+                        e,
+                    )
+                })
+                .map(|table| {
+                    let mut params: Vec<(String, ParamValue)> =
+                        table.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+                    params.sort_by(|a, b| a.0.cmp(&b.0));
+                    params
+                }),
+            None => directives.params.clone(),
+        };
+
+        let mut format_config = self.options.format_config.clone();
+        let mut optimizer_passes = self.options.optimizer_passes.clone();
+        if let Some(config) = &config {
+            format_config.merge(&config.format);
+            optimizer_passes = config.optimizer.passes.clone().or(optimizer_passes);
+        }
+        if let Some(pass) = optimizer_passes
+            .iter()
+            .flatten()
+            .find(|p| !OPTIMIZER_PASSES.contains(&p.as_str()))
+        {
+            error::raise(
+                TransformStage::Optimize,
+                None,
+                format!(
+                    "Unknown optimizer pass `{pass}`, expected one of: {}",
+                    OPTIMIZER_PASSES.join(", ")
+                ),
+            );
+        }
+
+        Settings {
+            directives,
+            params,
+            format_config,
+            optimizer_passes,
+            config,
+        }
+    }
+
+    /// The same pipeline without the params override, used for `__LJP:Include` files.
+    fn for_includes(&self) -> Pipeline {
+        Pipeline::new(self.options.clone())
+    }
+
+    /// Transform `code` and build the line mapping from the generated code back to the sources.
+    /// Errors are raised, see `error::catch()`.
+    pub(crate) fn run(&self, code: &str, lua_file_path: &str) -> (String, SourceMap) {
+        #[cfg(feature = "debug")]
+        let debug_prefix = format!("[Pipeline::run] <{lua_file_path}>");
+
+        let _guard = error::enter_file(lua_file_path);
+        lang_utils::refresh_comp_time_modules();
+
+        let settings = self.resolve(code, lua_file_path);
+        let directives = &settings.directives;
+        let preserve_lines = directives.preserve_lines == Some(true);
+
+        let (shebang, code) = directives::hide_shebang(code);
+        let code = code.as_str();
+
+        let final_code = if directives.frontend == Frontend::Teal {
+            error::set_stage(TransformStage::Teal);
+            let lua_code = lang_utils::convert_teal_to_lua(
+                lua_file_path,
+                code,
+                directives.syntax_only == Some(true),
+            )
+            .replace("bit32", "bit");
+            lua_code
+        } else {
+            code.to_string()
+        };
+
+        error::set_stage(TransformStage::Parse);
+        let ast = ast_utilis::parse_lua(&final_code);
+
+        let mut transformer = LuaTransformer::new();
+        transformer.file_path = Some(lua_file_path.to_string());
+        transformer.preserve_lines = preserve_lines;
+        transformer.pipeline = self.for_includes();
+        transformer.input_param_list = settings.params.clone().filter(|params| !params.is_empty());
+        error::set_stage(TransformStage::CompTime);
+        let mut new_ast = transformer.visit_ast(ast);
+        let included_sources = std::mem::take(&mut transformer.included_sources);
+
+        if directives.opt == Some(true) && !self.options.disable_optimizer {
+            error::set_stage(TransformStage::Optimize);
+            let mut optimizer = LuaOptimizer::new();
+            optimizer.passes = settings.optimizer_passes.clone();
+            let neww_ast = ast_utilis::parse_lua(&new_ast.to_string());
+            let lines = preserve_lines.then(|| ast_utilis::statement_lines(&neww_ast));
+            new_ast = optimizer.visit_ast(neww_ast);
+
+            // The passes rewrite expressions in place and append statements to the line of the annotated
+            // local, make sure that they did not move any statement
+            if let Some(lines) = lines {
+                let new_lines =
+                    ast_utilis::statement_lines(&ast_utilis::parse_lua(&new_ast.to_string()));
+                if let Some(line) = lines.iter().find(|line| !new_lines.contains(line)) {
+                    error::raise(
+                        TransformStage::Optimize,
+                        Some(*line),
+                        "The optimizer moved the statement of this line, which `preserve-lines` does not allow",
+                    );
+                }
+                if let Some(line) = new_lines.iter().find(|line| !lines.contains(line)) {
+                    error::raise(
+                        TransformStage::Optimize,
+                        Some(*line),
+                        "The optimizer moved a statement to this line, which `preserve-lines` does not allow",
+                    );
+                }
+            }
+        }
+
+        let mut new_content = new_ast.to_string();
+
+        if let Some(param_table) = settings.param_table() {
+            error::set_stage(TransformStage::Inject);
+            new_content = lang_utils::inject_global_vals(&new_content, param_table, preserve_lines);
+        }
+
+        if directives.frontend == Frontend::Luau {
+            error::set_stage(TransformStage::Luau);
+            new_content = lang_utils::convert_luau_to_lua(&new_content);
+        }
+
+        error::set_stage(TransformStage::Format);
+
+        if preserve_lines {
+            // Formatting re-layouts the code and removing comments drops the lines of multi-line comments
+            #[cfg(feature = "debug")]
+            log::debug!("{debug_prefix} preserve-lines, skip pretty/no-comment/format");
+        } else if directives.pretty == Some(true) {
+            #[cfg(feature = "debug")]
+            log::debug!("{debug_prefix} pretty");
+
+            // pretty == no-comment + format
+            new_content = lang_utils::format_lua_code(
+                &lang_utils::remove_lua_comments(&new_content),
+                &settings.format_config,
+            );
+        } else {
+            if directives.no_comment == Some(true) {
+                #[cfg(feature = "debug")]
+                log::debug!("{debug_prefix} no-comment");
+
+                new_content = lang_utils::remove_lua_comments(&new_content);
+            }
+
+            if directives.format == Some(true) {
+                #[cfg(feature = "debug")]
+                log::debug!("{debug_prefix} format");
+
+                new_content = lang_utils::format_lua_code(&new_content, &settings.format_config);
+            }
+        }
+
+        if let Some(shebang) = shebang {
+            new_content = directives::restore_shebang(shebang, &new_content);
+        }
+
+        // Teal lowering keeps the line layout(`preserve_newlines`), so `final_code` is line aligned with `code`
+        let source_map =
+            SourceMap::build(lua_file_path, &final_code, &new_content, &included_sources);
+
+        (new_content, source_map)
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+// Lines shorter than this (e.g. `end`, `}`, `do`) appear everywhere, they are only matched against nearby lines.
+const TRIVIAL_LINE_LEN: usize = 4;
+const SEARCH_WINDOW: usize = 64;
+const TRIVIAL_SEARCH_WINDOW: usize = 4;
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A line level mapping from the generated code back to the original luajit-pro sources.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    pub file: String,
+    pub sources: Vec<String>,
+    /// One entry per generated line: `(index into sources, 0-based source line)`
+    pub lines: Vec<Option<(usize, usize)>>,
+}
+
+/// The output of a transformed `__LJP:Include` file together with its own source map.
+#[derive(Debug, Clone)]
+pub struct IncludedSource {
+    pub lines: Vec<String>,
+    pub map: SourceMap,
+}
+
+impl IncludedSource {
+    pub fn new(code: &str, map: SourceMap) -> Self {
+        IncludedSource {
+            lines: code.lines().map(|l| l.to_string()).collect(),
+            map,
+        }
+    }
+}
+
+#[inline]
+fn normalize(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Where a generated line comes from in the origin.
+enum Origin {
+    Line(usize),
+    /// The line matches several origin lines, it is left unmapped rather than guessed
+    Ambiguous,
+    /// Not in the origin(e.g. code generated at comp-time)
+    Unknown,
+}
+
+/// Only whole lines are matched: the next origin line, otherwise a line found once in the window.
+fn find_origin_line(origin_lines: &[String], cursor: usize, key: &str) -> Origin {
+    let trivial = key.len() < TRIVIAL_LINE_LEN;
+    let window = if trivial {
+        TRIVIAL_SEARCH_WINDOW
+    } else {
+        SEARCH_WINDOW
+    };
+    let end = (cursor + window).min(origin_lines.len());
+    if cursor >= end {
+        return Origin::Unknown;
+    }
+
+    let mut matches = origin_lines[cursor..end]
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| *l == key)
+        .map(|(i, _)| cursor + i);
+    match (matches.next(), matches.next()) {
+        (Some(i), None) => Origin::Line(i),
+        (Some(i), Some(_)) if i == cursor => Origin::Line(i),
+        (Some(_), Some(_)) => Origin::Ambiguous,
+        (None, _) => Origin::Unknown,
+    }
+}
+
+fn encode_vlq(value: i64, out: &mut String) {
+    let mut v = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = v & 0b11111;
+        v >>= 5;
+        if v > 0 {
+            digit |= 0b100000;
+        }
+        out.push(BASE64_CHARS[digit as usize] as char);
+        if v == 0 {
+            break;
+        }
+    }
+}
+
+impl SourceMap {
+    /// Every generated line maps to the same line of `file`.
+    pub fn identity(file: &str, code: &str) -> Self {
+        SourceMap {
+            file: file.to_string(),
+            sources: vec![file.to_string()],
+            lines: (0..code.lines().count()).map(|i| Some((0, i))).collect(),
+        }
+    }
+
+    /// Build the mapping of `output` by aligning its lines with `origin`, which must be line aligned with `file`
+    /// (the code fed into the transformer, i.e. after Teal lowering), and with the lines of the included files.
+    /// Only whole lines are matched, lines that cannot be matched(e.g. code generated at comp-time) inherit the
+    /// mapping of the previous line and lines matching several source lines are left unmapped.
+    pub fn build(file: &str, origin: &str, output: &str, included: &[IncludedSource]) -> Self {
+        let mut map = SourceMap {
+            file: file.to_string(),
+            sources: vec![file.to_string()],
+            lines: Vec::new(),
+        };
+
+        let origin_lines: Vec<String> = origin.lines().map(normalize).collect();
+
+        // `None` for the lines found at several places of the included files
+        let mut included_index: HashMap<String, Option<(usize, usize)>> = HashMap::new();
+        for inc in included {
+            for (i, line) in inc.lines.iter().enumerate() {
+                let key = normalize(line);
+                if key.len() < TRIVIAL_LINE_LEN {
+                    continue;
+                }
+                if let Some(Some((src, src_line))) = inc.map.lines.get(i) {
+                    let origin = Some((map.add_source(&inc.map.sources[*src]), *src_line));
+                    included_index
+                        .entry(key)
+                        .and_modify(|mapped| {
+                            if *mapped != origin {
+                                *mapped = None;
+                            }
+                        })
+                        .or_insert(origin);
+                }
+            }
+        }
+
+        let mut cursor = 0;
+        let mut last = Some((0, 0));
+        for line in output.lines() {
+            let key = normalize(line);
+            // `Some(None)` for the ambiguous lines
+            let mapped = if key.is_empty() {
+                None
+            } else {
+                match find_origin_line(&origin_lines, cursor, &key) {
+                    Origin::Line(i) => {
+                        cursor = i + 1;
+                        Some(Some((0, i)))
+                    }
+                    Origin::Ambiguous => Some(None),
+                    Origin::Unknown => included_index.get(&key).cloned(),
+                }
+            };
+
+            match mapped {
+                Some(Some(origin)) => {
+                    last = Some(origin);
+                    map.lines.push(last);
+                }
+                Some(None) => map.lines.push(None),
+                None => map.lines.push(last),
+            }
+        }
+
+        map
+    }
+
+    pub fn add_source(&mut self, source: &str) -> usize {
+        match self.sources.iter().position(|s| s == source) {
+            Some(idx) => idx,
+            None => {
+                self.sources.push(source.to_string());
+                self.sources.len() - 1
+            }
+        }
+    }
+
+    /// Original `(file, 1-based line)` of the 1-based generated `line`.
+    pub fn lookup(&self, line: usize) -> Option<(&str, usize)> {
+        match self.lines.get(line.checked_sub(1)?) {
+            Some(Some((src, src_line))) => Some((self.sources[*src].as_str(), src_line + 1)),
+            _ => None,
+        }
+    }
+
+    /// Encode the mapping in Source Map v3 format, one segment at column 0 for each mapped line.
+    pub fn to_json(&self) -> String {
+        let mut mappings = String::new();
+        let (mut prev_src, mut prev_line) = (0i64, 0i64);
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                mappings.push(';');
+            }
+            if let Some((src, src_line)) = line {
+                encode_vlq(0, &mut mappings);
+                encode_vlq(*src as i64 - prev_src, &mut mappings);
+                encode_vlq(*src_line as i64 - prev_line, &mut mappings);
+                encode_vlq(0, &mut mappings);
+                prev_src = *src as i64;
+                prev_line = *src_line as i64;
+            }
+        }
+
+        serde_json::json!({
+            "version": 3,
+            "file": self.file,
+            "sources": self.sources,
+            "names": [],
+            "mappings": mappings,
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime};
+
+use crate::batch;
+use crate::deps;
+use crate::error::{self, Diagnostic, TransformError};
+
+/// mtime and size of a file, `None` if it does not exist
+type Fingerprint = Option<(SystemTime, u64)>;
+
+fn fingerprint(file_path: &str) -> Fingerprint {
+    let meta = std::fs::metadata(file_path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// The file was transformed and its cache entry updated
+    Built {
+        file: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    Failed(TransformError),
+    /// The file was deleted or no longer has the luajit-pro header
+    Removed(String),
+}
+
+/// Keeps the build cache of the source directories warm, see `poll()`.
+pub struct Watcher {
+    roots: Vec<String>,
+    /// Inputs of each file(the file itself, includes, comp-time dependencies) when it was last built
+    inputs: BTreeMap<String, BTreeMap<String, Fingerprint>>,
+}
+
+impl Watcher {
+    pub fn new(roots: &[String]) -> Self {
+        Watcher {
+            roots: roots.to_vec(),
+            inputs: BTreeMap::new(),
+        }
+    }
+
+    fn build(&mut self, file: &str) -> WatchEvent {
+        let ((ret, diagnostics), tracked) = deps::track(|| {
+            error::collect_diagnostics(|| error::catch(file, || crate::transform_lua_file(file)))
+        });
+
+        // A cache hit records nothing, the dependencies of the entry are used in that case
+        let mut inputs: BTreeSet<String> = tracked.files;
+        inputs.extend(crate::cached_dependencies(file));
+        inputs.insert(file.to_string());
+        self.inputs.insert(
+            file.to_string(),
+            inputs
+                .into_iter()
+                .map(|input| {
+                    let fingerprint = fingerprint(&input);
+                    (input, fingerprint)
+                })
+                .collect(),
+        );
+
+        match ret {
+            Ok(_) => WatchEvent::Built {
+                file: file.to_string(),
+                diagnostics,
+            },
+            Err(e) => WatchEvent::Failed(e),
+        }
+    }
+
+    /// Rebuild the new files and the ones whose inputs changed since the last call,
+    /// the first call builds every file.
+    pub fn poll(&mut self) -> Vec<WatchEvent> {
+        let files: BTreeSet<String> = self
+            .roots
+            .iter()
+            .flat_map(|root| batch::collect_files(root))
+            .collect();
+
+        let mut events = Vec::new();
+        let removed: Vec<String> = self
+            .inputs
+            .keys()
+            .filter(|file| !files.contains(*file))
+            .cloned()
+            .collect();
+        for file in removed {
+            self.inputs.remove(&file);
+            events.push(WatchEvent::Removed(file));
+        }
+
+        for file in &files {
+            let changed = match self.inputs.get(file) {
+                Some(inputs) => inputs.iter().any(|(input, fp)| fingerprint(input) != *fp),
+                None => true,
+            };
+            if changed {
+                events.push(self.build(file));
+            }
+        }
+        events
+    }
+
+    /// Call `poll()` every `interval` forever, passing the events to `on_event`.
+    pub fn run(&mut self, interval: Duration, mut on_event: impl FnMut(WatchEvent)) -> ! {
+        loop {
+            for event in self.poll() {
+                on_event(event);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
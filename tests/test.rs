@@ -1,9 +1,35 @@
 use luajit_pro_helper::*;
 
-use std::ffi::{CStr, CString};
+use std::ffi::{c_char, CStr, CString};
 
 const CARGO_PATH: &'static str = env!("CARGO_MANIFEST_DIR");
 
+/// Transform `file` through the loader entry point, panics with the error if the transformation failed.
+fn transform_file(file: &str) -> String {
+    let code = transform_lua(CString::new(file).unwrap().as_ptr());
+    assert!(
+        !code.is_null(),
+        "{}",
+        unsafe { CStr::from_ptr(ljp_last_error()) }.to_string_lossy()
+    );
+    let ret = unsafe { CStr::from_ptr(code) }
+        .to_str()
+        .unwrap()
+        .to_string();
+    ljp_free_string(code as *mut c_char);
+    ret
+}
+
+/// A fresh directory under `target/` whose `ljp.toml` keeps the build cache inside of it,
+/// instead of the `.luajit_pro` of the repository.
+fn project_dir(name: &str) -> String {
+    let dir = format!("{CARGO_PATH}/target/{name}");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{dir}/ljp.toml"), "cache_dir = \"cache\"\n").unwrap();
+    dir
+}
+
 #[test]
 fn test_lua() {
     let file_path = format!("{CARGO_PATH}/tests/main.lua");
@@ -33,3 +59,1029 @@ fn test_teal() {
 
     println!("{}", ret_code);
 }
+
+#[test]
+fn test_expand_lua() {
+    let file_path = format!("{CARGO_PATH}/tests/main.lua");
+
+    let ret_code = expand_lua_file(&file_path).unwrap();
+    assert_eq!(ret_code, transform_file(&file_path));
+}
+
+#[test]
+fn test_expand_teal() {
+    let file_path = format!("{CARGO_PATH}/tests/main.tl");
+
+    let ret_code = expand_lua_file(&file_path).unwrap();
+    assert_eq!(ret_code, transform_file(&file_path));
+}
+
+#[test]
+fn test_transform_error() {
+    let code = "--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    error(\"boom\")\nend\n";
+
+    let err = try_transform_lua_code(code, "error.lua", None).unwrap_err();
+    assert_eq!(err.file, "error.lua");
+    assert_eq!(err.stage, TransformStage::CompTime);
+    // The line of `error()` in the file, not in the comp-time block
+    assert_eq!(err.line, Some(3));
+    assert!(err.message.contains("error.lua:3:"), "{}", err.message);
+
+    let err = try_transform_lua_code("--[[luajit-pro]]\nlocal a = = 1\n", "syntax.lua", None)
+        .unwrap_err();
+    assert_eq!(err.stage, TransformStage::Parse);
+    assert_eq!(err.line, Some(2));
+
+    // A NUL byte can't be handed to C, the error is reported instead
+    let dir = format!("{CARGO_PATH}/target/test_transform_error");
+    std::fs::create_dir_all(&dir).unwrap();
+    let nul_file = format!("{dir}/nul.lua");
+    std::fs::write(&nul_file, "--[[luajit-pro]]\nprint(\"a\0b\")\n").unwrap();
+    let ret = transform_lua(CString::new(nul_file.as_str()).unwrap().as_ptr());
+    assert!(ret.is_null());
+    let err = unsafe { CStr::from_ptr(ljp_last_error()) }
+        .to_str()
+        .unwrap();
+    assert!(err.contains("NUL byte"), "{err}");
+}
+
+#[test]
+fn test_string() {
+    let code = "--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    return \"print(1)\"\nend\n";
+    assert!(transform_lua_string("=test", code).contains("print(1)"));
+
+    // Chunks without the header are untouched
+    assert_eq!(transform_lua_string("=plain", "print(2)"), "print(2)");
+
+    // Only `_G.__LJP` calls are transformed
+    let code = "--[[luajit-pro]]\n_G[\"print\"](3)\n_Gprint = print\n_Gprint(4)\n";
+    let ret = transform_lua_string("=global", code);
+    assert!(
+        ret.contains("_G[\"print\"](3)") && ret.contains("_Gprint(4)"),
+        "{ret}"
+    );
+}
+
+#[test]
+fn test_source_map() {
+    let code = "--[[luajit-pro, format]]\nlocal a = 1\n\nfunction __LJP:COMP_TIME()\n    return \"print(a)\"\nend\nprint(a + 1)\n";
+
+    let (ret_code, source_map) = transform_lua_code_with_map(code, "map.lua", None);
+    let line = ret_code
+        .lines()
+        .position(|l| l.contains("print(a + 1)"))
+        .unwrap()
+        + 1;
+    assert_eq!(source_map.lookup(line), Some(("map.lua", 7)));
+    assert!(source_map.to_json().contains("\"version\":3"));
+
+    // A line found twice ahead is left unmapped, lines are never matched partially
+    let origin = "print(1)\nprint(2)\nprint(2)\nlocal value = compute()\n";
+    let output = "print(0)\nprint(2)\nlocal value = compute() + 1\nprint(1)\n";
+    let source_map = SourceMap::build("map.lua", origin, output, &[]);
+    assert_eq!(source_map.lookup(2), None);
+    assert_ne!(source_map.lookup(3), Some(("map.lua", 4)));
+    assert_eq!(source_map.lookup(4), Some(("map.lua", 1)));
+}
+
+#[test]
+fn test_preserve_lines() {
+    let code = "--[[luajit-pro, preserve-lines, format]]\nfunction __LJP:COMP_TIME()\n    keep_line()\n    return \"local a = 1\\nlocal b = 2\\nlocal c = 3\\nlocal d = 4\\nlocal e = 5\"\nend\nprint(a)\n";
+
+    let ret_code = transform_lua_code(code, "preserve_lines.lua", None);
+    let lines: Vec<&str> = ret_code.lines().collect();
+    assert!(lines[1].contains("local a = 1"));
+    assert!(lines[4].contains("local e = 5"));
+    assert_eq!(lines[5].trim(), "print(a)");
+}
+
+/// Run `code` and return the lines it called `mark()` from, as seen by `debug.getinfo`.
+fn marked_lines(code: &str) -> Vec<i64> {
+    let lua = unsafe { mlua::Lua::unsafe_new() };
+    lua.load(
+        "MARKS = {} function mark() MARKS[#MARKS + 1] = debug.getinfo(2, 'l').currentline end",
+    )
+    .exec()
+    .unwrap();
+    lua.load(code).exec().unwrap();
+    lua.globals().get::<Vec<i64>>("MARKS").unwrap()
+}
+
+#[test]
+fn test_preserve_lines_runtime() {
+    let dir = project_dir("test_preserve_lines_runtime");
+    std::fs::write(
+        format!("{dir}/included.lua"),
+        "mark()\nlocal x = 1\nmark()\n",
+    )
+    .unwrap();
+    let pipeline = TransformOptions::new().build();
+
+    // Included code goes on the line of `__LJP:Include`, the comp-time code on the lines of its block
+    let code = format!("--[[luajit-pro, preserve-lines]]\nmark()\n__LJP:Include(\"{dir}/included\")\nmark()\nfunction __LJP:COMP_TIME()\n    return \"mark()\\nmark()\"\nend\nmark()\n");
+    let output = pipeline.transform(&code, "main.lua").unwrap();
+    assert_eq!(
+        marked_lines(&output.code),
+        vec![2, 3, 3, 4, 5, 6, 8],
+        "{}",
+        output.code
+    );
+
+    // The optimizer rewrites in place
+    let code = "--[[luajit-pro, preserve-lines, opt]]\nlocal --[[@comp_time_enum]] Color = {\n    RED = 1,\n}\nlocal --[[@used]] red = Color.RED\nmark()\nif red == 1 then\n    mark()\nend\n";
+    let output = pipeline.transform(code, "opt.lua").unwrap();
+    assert!(!output.code.contains("= Color.RED"), "{}", output.code);
+    assert_eq!(marked_lines(&output.code), vec![6, 8], "{}", output.code);
+
+    // Generated code longer than its block is folded, unless a string or a comment would be joined
+    let code = "--[[luajit-pro, preserve-lines]]\nfunction __LJP:COMP_TIME() return \"mark()\\nmark()\\nmark()\" end\nmark()\n";
+    let output = pipeline.transform(code, "fold.lua").unwrap();
+    assert_eq!(
+        marked_lines(&output.code),
+        vec![2, 2, 2, 3],
+        "{}",
+        output.code
+    );
+    for generated in ["mark()\\nlocal s = [[a\\nb]]", "mark() --# note\\nmark()"] {
+        let code = format!("--[[luajit-pro, preserve-lines]]\nfunction __LJP:COMP_TIME() return \"{generated}\" end\nmark()\n");
+        let err = pipeline.transform(&code, "fold.lua").unwrap_err();
+        assert_eq!(err.code, Some("LJP0205"), "{err:?}");
+        assert_eq!(err.line, Some(2), "{err:?}");
+    }
+}
+
+#[test]
+fn test_include_dependency() {
+    let dir = project_dir("test_include_dependency");
+
+    let main_file = format!("{dir}/main.lua");
+    std::fs::write(
+        &main_file,
+        format!("--[[luajit-pro]]\n__LJP:Include(\"{dir}/included\")\n"),
+    )
+    .unwrap();
+
+    let transform = || transform_file(&main_file);
+
+    std::fs::write(format!("{dir}/included.lua"), "print(\"v1\")\n").unwrap();
+    assert!(transform().contains("v1"));
+
+    // Editing the included file invalidates the cache entry of `main.lua`
+    std::fs::write(format!("{dir}/included.lua"), "print(\"v2\")\n").unwrap();
+    assert!(transform().contains("v2"));
+}
+
+#[test]
+fn test_include_preserve_lines() {
+    let dir = project_dir("test_include_preserve_lines");
+    let code = format!(
+        "--[[luajit-pro, preserve-lines]]\n__LJP:Include(\"{dir}/included\")\nprint(\"after\")\n"
+    );
+
+    // The included code is folded into the line of the call
+    std::fs::write(
+        format!("{dir}/included.lua"),
+        "local a = 1\n-- comment\nprint(a)\n",
+    )
+    .unwrap();
+    let ret = try_transform_lua_code(&code, &format!("{dir}/main.lua"), None).unwrap();
+    let lines: Vec<&str> = ret.lines().collect();
+    assert!(
+        lines[1].contains("local a = 1") && lines[1].contains("print(a)"),
+        "{ret}"
+    );
+    assert_eq!(lines[2].trim(), "print(\"after\")", "{ret}");
+
+    // A multi-line string can't be folded
+    std::fs::write(
+        format!("{dir}/included.lua"),
+        "local s = [[a\nb]]\nprint(s)\n",
+    )
+    .unwrap();
+    let err = try_transform_lua_code(&code, &format!("{dir}/main.lua"), None).unwrap_err();
+    assert_eq!(err.stage, TransformStage::Include);
+    assert_eq!(err.code, Some("LJP0301"));
+    assert_eq!(err.line, Some(2));
+}
+
+#[test]
+fn test_required_helper_dependency() {
+    let dir = project_dir("test_required_helper_dependency");
+    let main_file = format!("{dir}/main.lua");
+    std::fs::write(
+        &main_file,
+        format!("--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    package.path = \"{dir}/?.lua;\" .. package.path\n    return \"print('\" .. require(\"ljp_test_required_helper\").value .. \"')\"\nend\n"),
+    )
+    .unwrap();
+    let helper_file = format!("{dir}/ljp_test_required_helper.lua");
+    let transform = || transform_file(&main_file);
+
+    std::fs::write(&helper_file, "return { value = \"first\" }\n").unwrap();
+    assert!(transform().contains("first"));
+
+    // The module loaded by the first transformation is not reused once its file changed
+    std::fs::write(&helper_file, "return { value = \"second\" }\n").unwrap();
+    let ret = transform();
+    assert!(ret.contains("second"), "{ret}");
+    assert_eq!(transform(), ret);
+}
+
+#[test]
+fn test_comp_time_env_dependency() {
+    let dir = project_dir("test_comp_time_env_dependency");
+
+    let main_file = format!("{dir}/main.lua");
+    std::fs::write(
+        &main_file,
+        "--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    return \"print('\" .. env_vars.LJP_TEST_ENV_DEPENDENCY .. \"')\"\nend\n",
+    )
+    .unwrap();
+
+    // The env var is set for a child process, `set_var` would race with the other tests of this one
+    let transform = |value: &str| {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "test_comp_time_env_dependency_child",
+                "--exact",
+                "--nocapture",
+            ])
+            .env("LJP_TEST_ENV_DEPENDENCY", value)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(transform("first").contains("print('first')"));
+    assert!(transform("second").contains("print('second')"));
+}
+
+/// Run by `test_comp_time_env_dependency` with `LJP_TEST_ENV_DEPENDENCY` set, does nothing otherwise.
+#[test]
+fn test_comp_time_env_dependency_child() {
+    if std::env::var_os("LJP_TEST_ENV_DEPENDENCY").is_none() {
+        return;
+    }
+    let main_file = format!("{CARGO_PATH}/target/test_comp_time_env_dependency/main.lua");
+    println!("{}", transform_file(&main_file));
+}
+
+#[test]
+fn test_directives() {
+    let directives = Directives::parse("--[[luajit-pro, teal, opt, {optional_format = true}]]")
+        .unwrap()
+        .unwrap();
+    assert_eq!(directives.frontend, Frontend::Teal);
+    assert!(directives.opt == Some(true) && directives.format.is_none());
+    assert_eq!(directives.names(), vec!["teal", "opt"]);
+
+    assert!(Directives::parse("local a = 1").unwrap().is_none());
+    assert!(Directives::parse("--[[luajit-pro, no-comments]]").is_err());
+    assert!(Directives::parse("--[[luajit-pro, teal, luau]]").is_err());
+    assert!(Directives::parse("--[[luajit-pro, opt, opt]]").is_err());
+
+    let err = try_transform_lua_code("--[[luajit-pro, formatt]]\nprint(1)\n", "header.lua", None)
+        .unwrap_err();
+    assert_eq!(err.stage, TransformStage::Header);
+    assert_eq!(err.line, Some(1));
+}
+
+#[test]
+fn test_multiline_header() {
+    let header = "--[[luajit-pro\n    teal -- comment, opt\n    opt\n    {\n        N = 3, -- a number\n        TARGET = \"--openresty\",\n    }\n]]\n";
+    let code = format!("{header}local n: integer = N\nprint(n, TARGET)\n");
+    assert_eq!(header_end(&code), Some(header.len()));
+
+    let directives = Directives::parse(&code).unwrap().unwrap();
+    assert_eq!(directives.names(), vec!["teal", "opt"]);
+    assert_eq!(
+        directives.params,
+        Some(vec![
+            ("N".to_string(), ParamValue::Int(3)),
+            (
+                "TARGET".to_string(),
+                ParamValue::Str("--openresty".to_string())
+            ),
+        ])
+    );
+    assert!(Directives::parse("--[[luajit-pro\n    opt\n").is_err());
+    assert!(Directives::parse("--[[luajit-pro opt]]").is_err());
+
+    let dir = project_dir("test_multiline_header");
+    let file = format!("{dir}/main.lua");
+    std::fs::write(
+        &file,
+        "--[[luajit-pro\n    {\n        N = 3,\n    }\n]]\nlocal n = N\nprint(n)\n",
+    )
+    .unwrap();
+    let ret = transform_file(&file);
+    // The rewritten header keeps the line numbers
+    assert!(ret.starts_with("--[[luajit-pro"), "{ret}");
+    assert_eq!(ret.lines().nth(5), Some("local n = 3"), "{ret}");
+}
+
+#[test]
+fn test_typed_params() {
+    let code = "--[[luajit-pro, {LOG_LEVEL = 2, TARGET = \"openresty\", RATIO = 0.5, FEAT = 1}]]\nprint(LOG_LEVEL, TARGET, RATIO, FEAT)\n";
+    let directives = Directives::parse(code.lines().next().unwrap())
+        .unwrap()
+        .unwrap();
+    let params = directives.param_table().unwrap();
+    assert_eq!(params.as_ref().unwrap()["LOG_LEVEL"], ParamValue::Int(2));
+    assert_eq!(
+        params.as_ref().unwrap()["TARGET"],
+        ParamValue::Str("openresty".to_string())
+    );
+    assert_eq!(params.as_ref().unwrap()["FEAT"], ParamValue::Bool(true));
+
+    let ret = transform_lua_code(code, "typed_params.lua", params);
+    assert!(ret.contains("\"openresty\"") && ret.contains("0.5"));
+    assert!(!ret.contains("LOG_LEVEL,"));
+
+    assert!(Directives::parse("--[[luajit-pro, {TARGET = openresty}]]").is_err());
+    assert!(Directives::parse("--[[luajit-pro, {TARGET = \"openresty}]]").is_err());
+    assert_eq!(
+        ParamValue::parse_override("luajit", &ParamValue::Str("openresty".to_string())),
+        Ok(ParamValue::Str("luajit".to_string()))
+    );
+    assert!(ParamValue::parse_override("fast", &ParamValue::Int(2)).is_err());
+
+    // `0` and `1` are booleans, `{FEAT = 0}` switches a feature off
+    let code = "--[[luajit-pro, {FEAT = 0, LEVEL = 1.0}]]\nfunction __LJP:COMP_TIME()\n    if FEAT then return \"print('on')\" end\n    return \"print(\" .. (LEVEL + 1) .. \")\"\nend\n";
+    let directives = Directives::parse(code).unwrap().unwrap();
+    let params = directives.param_table().unwrap();
+    assert_eq!(params.as_ref().unwrap()["FEAT"], ParamValue::Bool(false));
+    assert_eq!(params.as_ref().unwrap()["LEVEL"], ParamValue::Float(1.0));
+    let ret = transform_lua_code(code, "bool_params.lua", params);
+    assert!(ret.contains("print(2") && !ret.contains("'on'"), "{ret}");
+
+    // Env vars override them the same way
+    assert_eq!(
+        ParamValue::parse_override("2", &ParamValue::Int(1)),
+        Ok(ParamValue::Int(2))
+    );
+    assert_eq!(
+        ParamValue::parse_override("1", &ParamValue::Int(3)),
+        Ok(ParamValue::Int(1))
+    );
+    assert_eq!(
+        ParamValue::parse_override("1", &ParamValue::Bool(false)),
+        Ok(ParamValue::Bool(true))
+    );
+}
+
+#[test]
+fn test_project_config() {
+    let dir = format!("{CARGO_PATH}/target/test_project_config");
+    std::fs::create_dir_all(format!("{dir}/src/app")).unwrap();
+    std::fs::write(
+        format!("{dir}/ljp.toml"),
+        "[params]\nLOG_LEVEL = 2\n\n[[files]]\nglob = \"src/**/*.lua\"\ndirectives = [\"format\"]\nparams = { TARGET = \"openresty\" }\n",
+    )
+    .unwrap();
+
+    // No header, the directives and params come from ljp.toml
+    let plain_file = format!("{dir}/src/app/plain.lua");
+    std::fs::write(&plain_file, "print(LOG_LEVEL,   TARGET)\n").unwrap();
+    assert!(file_matches_config(&plain_file));
+    let ret = expand_lua_file(&plain_file).unwrap();
+    assert!(ret.contains("print(2, \"openresty\")"), "{ret}");
+
+    // The header overrides the config
+    let header_file = format!("{dir}/src/app/header.lua");
+    std::fs::write(
+        &header_file,
+        "--[[luajit-pro, {LOG_LEVEL = 3}]]\nprint(LOG_LEVEL,   TARGET)\n",
+    )
+    .unwrap();
+    let ret = expand_lua_file(&header_file).unwrap();
+    assert!(ret.contains("print(3, \"openresty\")"), "{ret}");
+
+    // The header disables a directive enabled by the config
+    let (names, _) = explain_header(&plain_file, "--[[luajit-pro]]").unwrap();
+    assert_eq!(names, vec!["format"]);
+    let (names, _) = explain_header(&plain_file, "--[[luajit-pro, format = false]]").unwrap();
+    assert!(names.is_empty(), "{names:?}");
+    assert!(explain_header(&plain_file, "--[[luajit-pro, format, format = false]]").is_err());
+    assert!(explain_header(&plain_file, "--[[luajit-pro, format = 0]]").is_err());
+
+    let other_file = format!("{dir}/other.lua");
+    std::fs::write(&other_file, "print(1)\n").unwrap();
+    assert!(!file_matches_config(&other_file));
+}
+
+#[test]
+fn test_loader_config_check() {
+    let dir = format!("{CARGO_PATH}/target/test_loader_config_check");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = format!("{dir}/plain.lua");
+    std::fs::write(&file, "print(1)\n").unwrap();
+    let file = CString::new(file).unwrap();
+    assert_eq!(ljp_file_matches_config(file.as_ptr()), 0);
+
+    // The directory is remembered to have no config, the loader does not look again
+    std::fs::write(
+        format!("{dir}/ljp.toml"),
+        "[[files]]\nglob = \"*.lua\"\ndirectives = [\"format\"]\n",
+    )
+    .unwrap();
+    assert!(file_matches_config(&file.to_string_lossy()));
+    assert_eq!(ljp_file_matches_config(file.as_ptr()), 0);
+
+    // The config of a directory is remembered too, but reloaded when it changes
+    let project_dir = format!("{dir}/project");
+    std::fs::create_dir_all(&project_dir).unwrap();
+    let config_file = format!("{project_dir}/ljp.toml");
+    std::fs::write(
+        &config_file,
+        "[[files]]\nglob = \"*.lua\"\ndirectives = [\"format\"]\n",
+    )
+    .unwrap();
+    let file = format!("{project_dir}/plain.lua");
+    std::fs::write(&file, "print(1)\n").unwrap();
+    let file = CString::new(file).unwrap();
+    assert_eq!(ljp_file_matches_config(file.as_ptr()), 1);
+    std::fs::write(
+        &config_file,
+        "[[files]]\nglob = \"*.tl\"\ndirectives = [\"format\"]\n",
+    )
+    .unwrap();
+    let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    std::fs::File::options()
+        .write(true)
+        .open(&config_file)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert_eq!(ljp_file_matches_config(file.as_ptr()), 0);
+}
+
+#[test]
+fn test_pipeline() {
+    let pipeline = TransformOptions::new()
+        .format(true)
+        .param("LOG_LEVEL", ParamValue::Int(2))
+        .build();
+    let output = pipeline
+        .transform("print(LOG_LEVEL,   1)\n", "pipeline.lua")
+        .unwrap();
+    assert_eq!(output.code.trim(), "print(2, 1)");
+    assert!(output.source_map.is_some() && !output.from_cache);
+
+    let err = pipeline
+        .transform("--[[luajit-pro, nope]]\nprint(1)\n", "pipeline.lua")
+        .unwrap_err();
+    assert_eq!(err.stage, TransformStage::Header);
+
+    // The params are only seen by the comp-time code of this pipeline, even when it fails
+    let check_code = "function __LJP:COMP_TIME()\n    return \"print(\" .. tostring(rawget(_G, \"LOG_LEVEL\")) .. \")\"\nend\n";
+    let output = pipeline.transform(check_code, "pipeline.lua").unwrap();
+    assert!(output.code.contains("print(2)"), "{}", output.code);
+    let err = pipeline
+        .transform(
+            "function __LJP:COMP_TIME()\n    error(\"nope\")\nend\n",
+            "pipeline.lua",
+        )
+        .unwrap_err();
+    assert_eq!(err.stage, TransformStage::CompTime);
+    let other_pipeline = TransformOptions::new().build();
+    let output = other_pipeline.transform(check_code, "other.lua").unwrap();
+    assert!(output.code.contains("print(nil)"), "{}", output.code);
+
+    let dir = format!("{CARGO_PATH}/target/test_pipeline");
+    let _ = std::fs::remove_dir_all(&dir);
+    let pipeline = TransformOptions::new()
+        .cache(CachePolicy::ReadWrite, &dir)
+        .build();
+    let main_file = format!("{dir}/main.lua");
+    let code = "--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    return \"print(1)\"\nend\n";
+    assert!(!pipeline.transform(code, &main_file).unwrap().from_cache);
+    let output = pipeline.transform(code, &main_file).unwrap();
+    assert!(output.from_cache && output.code.contains("print(1)"));
+}
+
+#[test]
+fn test_capi() {
+    assert_eq!(ljp_version(), LJP_ABI_VERSION);
+
+    let options = LjpOptions {
+        abi_version: LJP_ABI_VERSION,
+        flags: LJP_FLAG_FORMAT,
+        frontend: LJP_FRONTEND_LUA,
+        cache_policy: LJP_CACHE_DISABLED,
+        cache_dir: std::ptr::null(),
+    };
+    let chunk_name = CString::new("=capi").unwrap();
+    let code = "print(1,   2)\n";
+    let result = ljp_transform_buffer(
+        chunk_name.as_ptr(),
+        code.as_ptr() as *const _,
+        code.len(),
+        &options,
+    );
+    assert!(ljp_result_ok(result));
+    assert!(ljp_result_error(result).is_null());
+    let mut size = 0;
+    let ret = unsafe { CStr::from_ptr(ljp_result_code(result, &mut size)) };
+    assert_eq!(ret.to_str().unwrap().trim(), "print(1, 2)");
+    assert_eq!(size, ret.to_bytes().len());
+    ljp_free_result(result);
+
+    // Without options, chunks without the header are returned unchanged
+    let result = ljp_transform_buffer(
+        chunk_name.as_ptr(),
+        code.as_ptr() as *const _,
+        code.len(),
+        std::ptr::null(),
+    );
+    let ret = unsafe { CStr::from_ptr(ljp_result_code(result, std::ptr::null_mut())) };
+    assert_eq!(ret.to_str().unwrap(), code);
+    ljp_free_result(result);
+
+    let code = "--[[luajit-pro, nope]]\nprint(1)\n";
+    let result = ljp_transform_buffer(
+        chunk_name.as_ptr(),
+        code.as_ptr() as *const _,
+        code.len(),
+        &options,
+    );
+    assert!(!ljp_result_ok(result));
+    assert!(ljp_result_code(result, std::ptr::null_mut()).is_null());
+    let stage = unsafe { CStr::from_ptr(ljp_result_error_stage(result)) };
+    assert_eq!(stage.to_str().unwrap(), "header");
+    let file = unsafe { CStr::from_ptr(ljp_result_error_file(result)) };
+    assert_eq!(file.to_str().unwrap(), "capi");
+    assert_eq!(ljp_result_error_line(result), 1);
+    ljp_free_result(result);
+
+    let options = LjpOptions {
+        abi_version: LJP_ABI_VERSION + 1,
+        ..options
+    };
+    let path = CString::new(format!("{CARGO_PATH}/tests/main.lua")).unwrap();
+    let result = ljp_transform_file(path.as_ptr(), &options);
+    assert!(!ljp_result_ok(result));
+    ljp_free_result(result);
+}
+
+#[test]
+fn test_c_header() {
+    let header_path = format!("{CARGO_PATH}/include/luajit_pro.h");
+    let mut generated = Vec::new();
+    cbindgen::generate(CARGO_PATH)
+        .expect("Failed to generate the C header")
+        .write(&mut generated);
+    if std::env::var_os("LJP_UPDATE_HEADER").is_some() {
+        std::fs::write(&header_path, &generated).unwrap();
+    }
+    assert!(
+        std::fs::read(&header_path).unwrap() == generated,
+        "include/luajit_pro.h is out of date with src/capi.rs, regenerate it with `LJP_UPDATE_HEADER=1 cargo test test_c_header`"
+    );
+}
+
+#[test]
+fn test_pregenerate() {
+    let dir = project_dir("test_pregenerate");
+    std::fs::create_dir_all(format!("{dir}/sub")).unwrap();
+    for i in 0..4 {
+        std::fs::write(
+            format!("{dir}/sub/ok{i}.lua"),
+            format!("--[[luajit-pro, {{N = {i}}}]]\nprint(N)\n"),
+        )
+        .unwrap();
+    }
+    std::fs::write(format!("{dir}/broken.lua"), "--[[luajit-pro]]\nprint(\n").unwrap();
+    std::fs::write(format!("{dir}/plain.lua"), "print(1)\n").unwrap();
+    std::fs::write(format!("{dir}/notes.txt"), "--[[luajit-pro]]\n").unwrap();
+
+    let files = collect_files(&dir);
+    assert_eq!(files.len(), 5, "{files:?}");
+
+    let report = pregenerate(&files, 2);
+    assert_eq!(report.transformed, 4);
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].file.ends_with("broken.lua"));
+}
+
+#[test]
+fn test_watcher() {
+    let dir = project_dir("test_watcher");
+    std::fs::create_dir_all(format!("{dir}/src")).unwrap();
+    std::fs::write(format!("{dir}/inc.lua"), "print(\"v1\")\n").unwrap();
+    std::fs::write(
+        format!("{dir}/src/main.lua"),
+        format!("--[[luajit-pro]]\n__LJP:Include(\"{dir}/inc\")\n"),
+    )
+    .unwrap();
+
+    let mut watcher = Watcher::new(&[format!("{dir}/src")]);
+    let events = watcher.poll();
+    assert!(
+        matches!(&events[..], [WatchEvent::Built { .. }]),
+        "{events:?}"
+    );
+    assert!(watcher.poll().is_empty());
+
+    // Editing the included file rebuilds `main.lua`
+    std::fs::write(format!("{dir}/inc.lua"), "print(\"v22\")\n").unwrap();
+    let events = watcher.poll();
+    assert!(
+        matches!(&events[..], [WatchEvent::Built { .. }]),
+        "{events:?}"
+    );
+
+    std::fs::write(format!("{dir}/src/main.lua"), "--[[luajit-pro]]\nprint(\n").unwrap();
+    assert!(matches!(&watcher.poll()[..], [WatchEvent::Failed(_)]));
+
+    std::fs::remove_file(format!("{dir}/src/main.lua")).unwrap();
+    assert!(matches!(&watcher.poll()[..], [WatchEvent::Removed(_)]));
+}
+
+#[test]
+fn test_watcher_required_helper() {
+    let dir = project_dir("test_watcher_required_helper");
+    std::fs::create_dir_all(format!("{dir}/src")).unwrap();
+    let helper_file = format!("{dir}/ljp_test_watched_helper.lua");
+    std::fs::write(&helper_file, "return { value = \"first\" }\n").unwrap();
+    let main_file = format!("{dir}/src/main.lua");
+    std::fs::write(
+        &main_file,
+        format!("--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    package.path = \"{dir}/?.lua;\" .. package.path\n    return \"print('\" .. require(\"ljp_test_watched_helper\").value .. \"')\"\nend\n"),
+    )
+    .unwrap();
+
+    let mut watcher = Watcher::new(&[format!("{dir}/src")]);
+    assert!(matches!(&watcher.poll()[..], [WatchEvent::Built { .. }]));
+    assert!(transform_file(&main_file).contains("first"));
+
+    // The rebuild runs against the changed helper, not the module loaded by the first build
+    std::fs::write(&helper_file, "return { value = \"second\" }\n").unwrap();
+    assert!(matches!(&watcher.poll()[..], [WatchEvent::Built { .. }]));
+    let ret = transform_file(&main_file);
+    assert!(ret.contains("second"), "{ret}");
+}
+
+#[test]
+fn test_build_tree() {
+    let dir = format!("{CARGO_PATH}/target/test_build_tree");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(format!("{dir}/src/lib")).unwrap();
+    std::fs::write(
+        format!("{dir}/src/lib/gen.lua"),
+        "--[[luajit-pro, {N = 3}]] local n = N\nfunction __LJP:COMP_TIME()\n    return \"print(1)\"\nend\n",
+    )
+    .unwrap();
+    std::fs::write(format!("{dir}/src/plain.lua"), "print(2)\n").unwrap();
+    std::fs::write(format!("{dir}/src/data.txt"), "data\n").unwrap();
+
+    let report = build_tree(&format!("{dir}/src"), &format!("{dir}/out"), 2);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.transformed.len(), 1);
+    assert_eq!(report.copied, vec!["data.txt", "plain.lua"]);
+
+    let generated = std::fs::read_to_string(format!("{dir}/out/lib/gen.lua")).unwrap();
+    assert!(!generated.contains("luajit-pro"), "{generated}");
+    assert!(!generated.contains("COMP_TIME"), "{generated}");
+    assert!(generated.contains("local n = 3"), "{generated}");
+    let plain = std::fs::read_to_string(format!("{dir}/out/plain.lua")).unwrap();
+    assert_eq!(plain, "print(2)\n");
+}
+
+#[test]
+fn test_bytecode_cache() {
+    let dir = format!("{CARGO_PATH}/target/test_bytecode_cache");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        format!("{dir}/ljp.toml"),
+        "cache_dir = \"cache\"\n\n[bytecode]\nenabled = true\nstrip = true\n",
+    )
+    .unwrap();
+    let file = format!("{dir}/main.lua");
+    std::fs::write(&file, "--[[luajit-pro, {N = 1}]]\nprint(N)\n").unwrap();
+
+    let path = CString::new(file.as_str()).unwrap();
+    for _ in 0..2 {
+        // The second load is a cache hit
+        let result = ljp_transform_file(path.as_ptr(), std::ptr::null());
+        assert!(ljp_result_ok(result));
+        let mut size = 0;
+        let code = ljp_result_code(result, &mut size);
+        let code = unsafe { std::slice::from_raw_parts(code as *const u8, size) };
+        assert!(code.starts_with(b"\x1bLJ"), "{code:?}");
+        ljp_free_result(result);
+    }
+
+    // `loadfile(path, "t")` gets the generated code, a removed bytecode file is written again
+    let load = |allow_bytecode: bool| {
+        let result = ljp_load_file(path.as_ptr(), allow_bytecode);
+        assert!(ljp_result_ok(result));
+        let mut size = 0;
+        let code = ljp_result_code(result, &mut size);
+        let code = unsafe { std::slice::from_raw_parts(code as *const u8, size) }.to_vec();
+        ljp_free_result(result);
+        code
+    };
+    let text = load(false);
+    assert!(String::from_utf8(text).unwrap().contains("print(1)"));
+    for entry in std::fs::read_dir(format!("{dir}/cache")).unwrap() {
+        let entry = entry.unwrap().path();
+        if entry.extension().is_some_and(|ext| ext == "luac") {
+            std::fs::remove_file(entry).unwrap();
+        }
+    }
+    assert!(load(true).starts_with(b"\x1bLJ"));
+}
+
+#[test]
+fn test_diagnostics() {
+    let dir = format!("{CARGO_PATH}/target/test_diagnostics");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = format!("{dir}/params.lua");
+    std::fs::write(
+        &file,
+        "--[[luajit-pro]]\nfunction __LJP:COMP_TIME(a, b)\n    return \"\"\nend\n",
+    )
+    .unwrap();
+
+    let err = expand_lua_file(&file).unwrap_err();
+    assert_eq!(err.code, Some("LJP0201"));
+    assert_eq!(err.line, Some(2));
+    assert_eq!(err.column, Some(29));
+    assert!(err.hint.is_some());
+
+    let rendered = err.render();
+    assert!(rendered.starts_with("error[LJP0201]"), "{rendered}");
+    assert!(
+        rendered.contains("function __LJP:COMP_TIME(a, b)"),
+        "{rendered}"
+    );
+    assert!(rendered.contains("^"), "{rendered}");
+    assert!(err.to_json().contains("\"severity\":\"error\""));
+}
+
+#[test]
+fn test_comp_time_error_line_in_include() {
+    let dir = project_dir("test_comp_time_error_line_in_include");
+
+    let main_file = format!("{dir}/main.lua");
+    std::fs::write(
+        &main_file,
+        format!("--[[luajit-pro]]\n__LJP:Include(\"{dir}/included\")\n"),
+    )
+    .unwrap();
+    let included_file = format!("{dir}/included.lua");
+    std::fs::write(
+        &included_file,
+        "--[[luajit-pro]]\nlocal a = 1\n\nfunction __LJP:COMP_TIME() local x = 1\n    error(\"boom\")\nend\n",
+    )
+    .unwrap();
+
+    let err = expand_lua_file(&main_file).unwrap_err();
+    assert!(err.file.ends_with("included.lua"), "{}", err.file);
+    assert_eq!(err.line, Some(5));
+    assert!(err.message.contains("included.lua:5:"), "{}", err.message);
+}
+
+#[test]
+fn test_shebang_header() {
+    let code = "\u{feff}#!/usr/bin/env luajit\n--[[luajit-pro, {N = 2}]]\nprint(N)\n";
+    assert_eq!(
+        Directives::parse(code).unwrap().unwrap().params,
+        Some(vec![("N".to_string(), ParamValue::Int(2))])
+    );
+    let ret = transform_lua_string("=script", code);
+    assert!(ret.starts_with("#!/usr/bin/env luajit\n"), "{ret}");
+
+    let err = try_transform_lua_code(
+        "#!/usr/bin/env luajit\n--[[luajit-pro, formatt]]\n",
+        "script.lua",
+        None,
+    )
+    .unwrap_err();
+    assert_eq!(err.line, Some(2));
+
+    let dir = project_dir("test_shebang_header");
+    let file = format!("{dir}/script.lua");
+    std::fs::write(&file, code).unwrap();
+    let ret = transform_file(&file);
+    let mut lines = ret.lines();
+    assert_eq!(lines.next(), Some("#!/usr/bin/env luajit"));
+    assert_eq!(lines.next(), Some("--[[luajit-pro, {N = 2}]]"));
+}
+
+#[test]
+fn test_cache_integrity() {
+    let dir = project_dir("test_cache_integrity");
+    let file = format!("{dir}/main.lua");
+    std::fs::write(
+        &file,
+        "--[[luajit-pro, {N = 1}]]\nprint(N)\nprint(\"done\")\n",
+    )
+    .unwrap();
+
+    let transform = || transform_file(&file);
+    let expected = transform();
+    assert!(expected.contains("done"));
+
+    let cache_files = || -> Vec<String> {
+        std::fs::read_dir(format!("{dir}/cache"))
+            .unwrap()
+            .map(|f| f.unwrap().path().to_string_lossy().into_owned())
+            .collect()
+    };
+    // No lock or temp files are left behind
+    assert!(
+        cache_files()
+            .iter()
+            .all(|f| !f.ends_with(".lock") && !f.ends_with(".tmp")),
+        "{:?}",
+        cache_files()
+    );
+
+    // A truncated entry fails the checksum and is generated again
+    let entry = cache_files()
+        .into_iter()
+        .find(|f| f.ends_with(".lua"))
+        .unwrap();
+    let content = std::fs::read_to_string(&entry).unwrap();
+    std::fs::write(&entry, &content[..content.len() / 2]).unwrap();
+    assert_eq!(transform(), expected);
+    assert_eq!(std::fs::read_to_string(&entry).unwrap(), expected);
+}
+
+#[test]
+fn test_cache_backends() {
+    let dir = format!("{CARGO_PATH}/target/test_cache_backends");
+    let _ = std::fs::remove_dir_all(&dir);
+    let main_file = format!("{dir}/main.lua");
+    let code = "--[[luajit-pro]]\nfunction __LJP:COMP_TIME()\n    return \"print(1)\"\nend\n";
+
+    // The memory backend never touches the disk
+    let memory = TransformOptions::new()
+        .cache(CachePolicy::ReadWrite, format!("{dir}/memory"))
+        .cache_backend(std::sync::Arc::new(MemoryBackend::default()))
+        .build();
+    assert!(!memory.transform(code, &main_file).unwrap().from_cache);
+    assert!(memory.transform(code, &main_file).unwrap().from_cache);
+    assert!(!std::fs::exists(format!("{dir}/memory")).unwrap());
+
+    // A bundle packed from the fs cache serves the same entries, read-only
+    let cache_dir = format!("{dir}/cache");
+    let fs = TransformOptions::new()
+        .cache(CachePolicy::ReadWrite, &cache_dir)
+        .build();
+    fs.transform(code, &main_file).unwrap();
+    let bundle_file = format!("{dir}/cache.ljpb");
+    assert!(write_bundle(&FsBackend, &cache_dir, &bundle_file).unwrap() >= 2);
+
+    let bundle = BundleBackend::open(std::path::Path::new(&bundle_file)).unwrap();
+    assert!(bundle.read_only());
+    let bundled = TransformOptions::new()
+        .cache(CachePolicy::ReadWrite, format!("{dir}/elsewhere"))
+        .cache_backend(std::sync::Arc::new(bundle))
+        .build();
+    let output = bundled.transform(code, &main_file).unwrap();
+    assert!(output.from_cache && output.code.contains("print(1)"));
+    // A changed source misses the bundle and is transformed without being stored
+    let changed = code.replace("print(1)", "print(2)");
+    let output = bundled.transform(&changed, &main_file).unwrap();
+    assert!(!output.from_cache && output.code.contains("print(2)"));
+}
+
+#[test]
+fn test_cache_dir_at_project_root() {
+    let dir = format!("{CARGO_PATH}/target/test_cache_dir_at_project_root");
+    let _ = std::fs::remove_dir_all(&dir);
+    // The nearest repository root is the project root when there is no ljp.toml
+    std::fs::create_dir_all(format!("{dir}/.hg")).unwrap();
+    std::fs::create_dir_all(format!("{dir}/src/nested")).unwrap();
+    let file = format!("{dir}/src/nested/main.lua");
+    std::fs::write(&file, "--[[luajit-pro, {N = 3}]]\nprint(N)\n").unwrap();
+
+    let code = transform_file(&file);
+    assert!(code.contains("print(3)"), "{code}");
+    let entries: Vec<_> = std::fs::read_dir(format!("{dir}/.luajit_pro/build_cache"))
+        .unwrap()
+        .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(
+        entries.iter().any(|name| name.starts_with("main.lua.")),
+        "{entries:?}"
+    );
+    assert!(!std::fs::exists(format!("{dir}/src/nested/.luajit_pro")).unwrap());
+}
+
+#[test]
+fn test_user_cache_dir() {
+    // Outside of the repository, so that the files belong to no project
+    let dir = std::env::temp_dir().join("ljp_test_user_cache_dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    for name in ["a", "b"] {
+        std::fs::create_dir_all(dir.join(name)).unwrap();
+        std::fs::write(
+            dir.join(name).join("main.lua"),
+            "--[[luajit-pro, {N = 3}]]\nprint(N)\n",
+        )
+        .unwrap();
+    }
+    let cache_home = format!("{CARGO_PATH}/target/test_user_cache_dir");
+    let _ = std::fs::remove_dir_all(&cache_home);
+
+    // `XDG_CACHE_HOME` is set for a child process, `set_var` would race with the other tests of this one
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_user_cache_dir_child", "--exact", "--nocapture"])
+        .env("XDG_CACHE_HOME", &cache_home)
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    // Both files share one cache dir, which is the one of the cache commands run outside of a project
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains(&format!("cache dir: {cache_home}/luajit_pro/build_cache")),
+        "{stdout}"
+    );
+    assert!(stdout.contains("entries: 2"), "{stdout}");
+}
+
+/// Run by `test_user_cache_dir` with `XDG_CACHE_HOME` set, does nothing otherwise.
+#[test]
+fn test_user_cache_dir_child() {
+    let Some(cache_home) = std::env::var_os("XDG_CACHE_HOME") else {
+        return;
+    };
+    if !cache_home
+        .to_string_lossy()
+        .ends_with("test_user_cache_dir")
+    {
+        return;
+    }
+    for name in ["a", "b"] {
+        assert!(transform_file(&format!("{name}/main.lua")).contains("print(3)"));
+    }
+    println!("cache dir: {}", cache_dir());
+    println!("entries: {}", cache_entries().len());
+}
+
+#[test]
+fn test_cache_index() {
+    let dir = project_dir("test_cache_index");
+    let a_file = format!("{dir}/a.lua");
+    let b_file = format!("{dir}/b.lua");
+    std::fs::write(&a_file, "--[[luajit-pro, {N = 3}]]\nprint(N)\n").unwrap();
+    std::fs::write(&b_file, "--[[luajit-pro, {N = 2}]]\nprint(N)\n").unwrap();
+
+    // The counters are per process and other tests run concurrently
+    let before = cache_stats();
+    transform_file(&a_file);
+    transform_file(&b_file);
+    assert!(cache_stats().misses >= before.misses + 2);
+    let before = cache_stats();
+    assert!(transform_file(&a_file).contains("print(3)"));
+    assert!(cache_stats().hits > before.hits);
+
+    let cache = BuildCache::of(&dir);
+    assert!(
+        cache.dir.ends_with("test_cache_index/cache"),
+        "{}",
+        cache.dir
+    );
+    let entries = cache.entries();
+    assert_eq!(entries.len(), 2, "{entries:?}");
+    let a = entries
+        .iter()
+        .find(|e| e.source.ends_with("a.lua"))
+        .unwrap();
+    let b = entries
+        .iter()
+        .find(|e| e.source.ends_with("b.lua"))
+        .unwrap();
+    assert!(a.fresh && !a.orphan && a.size > 0 && !a.key.is_empty());
+    assert!(a.created.is_some() && a.last_hit.is_some());
+    assert!(b.last_hit.is_none());
+    assert!(a.deps.iter().any(|dep| dep.ends_with("ljp.toml")));
+
+    std::fs::remove_file(&b_file).unwrap();
+    assert_eq!(cache.remove_orphans(), 1);
+    assert_eq!(cache.entries().len(), 1);
+
+    assert_eq!(
+        cache.prune_by(Some(std::time::Duration::from_secs(3600)), None),
+        0
+    );
+    assert_eq!(cache.prune_by(None, Some(0)), 1);
+    assert!(cache.entries().is_empty());
+}